@@ -1,53 +1,79 @@
-use std::fs;
 use std::process;
 
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 use serde_json::from_str;
 use simulation::models::Manifest;
 use simulation::Simulation;
+use tokio::signal::unix::{signal, Signal, SignalKind};
 use tracing::{error, info};
-use ts_core::{Output, PortRange, Protocol, TrafficConfig, TrafficShaper};
+use ts_core::{
+    default_backend, dry_run_backend, AddressFamily, Bandwidth, Delay, DelayDistribution, FlowMask,
+    GilbertElliott, Interfaces, IpNetwork, Jitter, LinkConditions, Output, PortRange, Protocol,
+    QueueSize, Red, Reorder, TrafficConfig, TrafficShaper, TrafficShapingError,
+};
 
 #[derive(Parser)]
 #[command(name = "traffic-shaper")]
-#[command(about = "A CLI tool for traffic shaping on macOS", long_about = None)]
+#[command(about = "A CLI tool for traffic shaping on macOS and Linux", long_about = None)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// Print the pf rules and every command that would run, in order, without changing the
+    /// system. Needs no root.
+    #[arg(long, global = true)]
+    dry_run: bool,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     /// Start traffic shaping with the specified configuration
     Start {
-        /// Packet loss percentage (0.0 to 100.0)
-        #[arg(long, value_parser = validate_percentage)]
-        packet_loss: f32,
+        #[command(flatten)]
+        conditions: Box<ConditionArgs>,
 
-        /// Additional latency in milliseconds
+        /// Target protocol: tcp, udp, both, icmp, icmp6, any, or a protocol name or number (e.g., gre, 50)
         #[arg(long)]
-        latency: u32,
+        protocol: Protocol,
 
-        /// Maximum bandwidth in bits per second
-        #[arg(long)]
-        bandwidth: u64,
+        /// Address families to target: inet, inet6 or both
+        #[arg(long, default_value = "both")]
+        family: AddressFamily,
 
-        /// Target protocol (tcp, udp, or both)
-        #[arg(long, value_parser = parse_protocol)]
-        protocol: Protocol,
+        /// Optional source ports, ranges or service names, comma separated (e.g., 443,5000-5100,https)
+        #[arg(long, value_delimiter = ',')]
+        src_ports: Vec<PortRange>,
+
+        /// Optional destination ports, ranges or service names, comma separated
+        #[arg(long, value_delimiter = ',')]
+        dst_ports: Vec<PortRange>,
+
+        /// Optional source hosts or CIDR blocks, comma separated (e.g., 10.0.0.0/8,2001:db8::1)
+        #[arg(long, value_delimiter = ',')]
+        src_addresses: Vec<IpNetwork>,
+
+        /// Optional destination hosts or CIDR blocks, comma separated
+        #[arg(long, value_delimiter = ',')]
+        dst_addresses: Vec<IpNetwork>,
 
-        /// Optional target port range (format: start-end, e.g., 80-8080)
-        #[arg(long, value_parser = parse_port_range)]
-        src_ports: Option<(u16, u16)>,
+        /// Interfaces to shape: all, loopback, non-loopback or a comma separated list of names
+        #[arg(long, default_value = "all")]
+        interfaces: Interfaces,
 
-        /// Optional target port range (format: start-end, e.g., 80-8080)
-        #[arg(long, value_parser = parse_port_range)]
-        dst_ports: Option<(u16, u16)>,
+        /// Give every flow its own conditions: none, all (the 5-tuple) or a comma separated list
+        /// of src-address, dst-address, src-port, dst-port and proto
+        #[arg(long, default_value = "none")]
+        flow_mask: FlowMask,
 
         #[arg(long, value_parser = parse_output)]
         report_output: Option<Output>,
+
+        /// Keep running and stop shaping on Ctrl-C, SIGTERM or SIGHUP, instead of leaving it in
+        /// place until the stop command
+        #[arg(long)]
+        foreground: bool,
     },
-    /// Stop traffic shaping and restore original configuration
+    /// Stop traffic shaping, undoing the changes made by start
     Stop,
 
     Simulation {
@@ -56,6 +82,106 @@ enum Commands {
     },
 }
 
+/// Conditions shaping both directions of the traffic
+#[derive(Args)]
+struct ConditionArgs {
+    /// Packet loss percentage (0.0 to 100.0)
+    #[arg(long, value_parser = validate_percentage)]
+    packet_loss: f32,
+
+    /// Optional bursty loss replacing the packet loss, as percentages
+    /// (format: to_bad,to_good[,bad_loss[,good_loss]], e.g., 1,25)
+    #[arg(long, value_parser = parse_burst_loss)]
+    burst_loss: Option<GilbertElliott>,
+
+    /// Additional latency, in milliseconds or with a unit (e.g., 250ms, 1.2s)
+    #[arg(long)]
+    latency: Delay,
+
+    /// Optional latency variation, in milliseconds or with a unit
+    #[arg(long)]
+    jitter: Option<Delay>,
+
+    /// Distribution of the jitter (uniform, normal, or pareto)
+    #[arg(long, value_parser = parse_distribution, default_value = "uniform")]
+    jitter_distribution: DelayDistribution,
+
+    /// Correlation percentage of the jitter of consecutive packets (0.0 to 100.0)
+    #[arg(long, value_parser = validate_percentage, default_value_t = 0.0)]
+    jitter_correlation: f32,
+
+    /// Maximum bandwidth, in bits per second or with a unit (e.g., 10Mbit, 1.5MB/s), 0 for unlimited
+    #[arg(long)]
+    bandwidth: Bandwidth,
+
+    /// Optional percentage of packets sent ahead of earlier ones (0.0 to 100.0)
+    #[arg(long, value_parser = validate_percentage)]
+    reorder: Option<f32>,
+
+    /// Only every Nth packet may be reordered
+    #[arg(long, default_value_t = 1)]
+    reorder_gap: u32,
+
+    /// Duplicated packet percentage (0.0 to 100.0)
+    #[arg(long, value_parser = validate_percentage, default_value_t = 0.0)]
+    duplicate: f32,
+
+    /// Percentage of packets with a flipped bit (0.0 to 100.0)
+    #[arg(long, value_parser = validate_percentage, default_value_t = 0.0)]
+    corrupt: f32,
+
+    /// Optional queue capacity, in packets or in bytes (e.g., 100 or 64000bytes)
+    #[arg(long, value_parser = parse_queue)]
+    queue: Option<QueueSize>,
+
+    /// Optional random early detection, in the unit of the queue
+    /// (format: weight,min_threshold,max_threshold,max_probability, e.g., 0.002,5,15,0.1)
+    #[arg(long, value_parser = parse_red)]
+    red: Option<Red>,
+
+    /// Use gentle RED, raising the drop probability gradually past the maximum threshold
+    #[arg(long, requires = "red")]
+    gentle_red: bool,
+
+    /// Mark ECN capable packets instead of dropping them
+    #[arg(long)]
+    ecn: bool,
+}
+
+impl ConditionArgs {
+    fn link_conditions(&self) -> Result<LinkConditions, TrafficShapingError> {
+        let mut conditions = LinkConditions::new(self.packet_loss, self.latency, self.bandwidth)?
+            .with_duplicate(self.duplicate)?
+            .with_corrupt(self.corrupt)?
+            .with_ecn(self.ecn);
+
+        if let Some(deviation) = self.jitter {
+            conditions = conditions.with_jitter(Jitter::new(
+                deviation,
+                self.jitter_distribution,
+                self.jitter_correlation,
+            )?);
+        }
+        if let Some(model) = &self.burst_loss {
+            conditions = conditions.with_burst_loss(model.clone());
+        }
+        if let Some(probability) = self.reorder {
+            conditions = conditions.with_reorder(Reorder::new(probability, 0.0, self.reorder_gap)?);
+        }
+        if let Some(queue) = self.queue {
+            conditions = conditions.with_queue(queue);
+        }
+        if let Some(red) = &self.red {
+            conditions = conditions.with_red(Red {
+                gentle: self.gentle_red,
+                ..red.clone()
+            });
+        }
+
+        Ok(conditions)
+    }
+}
+
 fn validate_percentage(s: &str) -> Result<f32, String> {
     let value: f32 = s.parse().map_err(|_| "Invalid percentage value")?;
     if !(0.0..=100.0).contains(&value) {
@@ -64,29 +190,71 @@ fn validate_percentage(s: &str) -> Result<f32, String> {
     Ok(value)
 }
 
-fn parse_protocol(s: &str) -> Result<Protocol, String> {
+fn parse_distribution(s: &str) -> Result<DelayDistribution, String> {
     match s.to_lowercase().as_str() {
-        "tcp" => Ok(Protocol::Tcp),
-        "udp" => Ok(Protocol::Udp),
-        "both" => Ok(Protocol::Both),
-        _ => Err("Protocol must be one of: tcp, udp, both".to_string()),
+        "uniform" => Ok(DelayDistribution::Uniform),
+        "normal" => Ok(DelayDistribution::Normal),
+        "pareto" => Ok(DelayDistribution::Pareto),
+        _ => Err("Distribution must be one of: uniform, normal, pareto".to_string()),
     }
 }
 
-fn parse_port_range(s: &str) -> Result<(u16, u16), String> {
-    let parts: Vec<&str> = s.split('-').collect();
-    if parts.len() != 2 {
-        return Err("Port range must be in format: start-end".to_string());
-    }
+fn parse_burst_loss(s: &str) -> Result<GilbertElliott, String> {
+    let values = s
+        .split(',')
+        .map(|value| value.trim().parse::<f32>())
+        .collect::<Result<Vec<f32>, _>>()
+        .map_err(|_| "Invalid percentage value")?;
 
-    let start: u16 = parts[0].parse().map_err(|_| "Invalid start port number")?;
-    let end: u16 = parts[1].parse().map_err(|_| "Invalid end port number")?;
+    let (to_bad, to_good, bad_loss, good_loss) = match values.as_slice() {
+        [to_bad, to_good] => (*to_bad, *to_good, 100.0, 0.0),
+        [to_bad, to_good, bad_loss] => (*to_bad, *to_good, *bad_loss, 0.0),
+        [to_bad, to_good, bad_loss, good_loss] => (*to_bad, *to_good, *bad_loss, *good_loss),
+        _ => {
+            return Err(
+                "Burst loss must be in format: to_bad,to_good[,bad_loss[,good_loss]]".to_string(),
+            )
+        }
+    };
+
+    GilbertElliott::new(to_bad, to_good, bad_loss, good_loss).map_err(|e| e.to_string())
+}
 
-    if start > end {
-        return Err("Start port must be less than or equal to end port".to_string());
+fn parse_queue(s: &str) -> Result<QueueSize, String> {
+    match s.strip_suffix("bytes") {
+        Some(bytes) => bytes
+            .parse()
+            .map(QueueSize::Bytes)
+            .map_err(|_| "Invalid queue size in bytes".to_string()),
+        None => s
+            .parse()
+            .map(QueueSize::Slots)
+            .map_err(|_| "Queue size must be a number of packets or end with 'bytes'".to_string()),
     }
+}
+
+fn parse_red(s: &str) -> Result<Red, String> {
+    let parts: Vec<&str> = s.split(',').map(str::trim).collect();
+    let [weight, min_threshold, max_threshold, max_probability] = parts.as_slice() else {
+        return Err(
+            "RED must be in format: weight,min_threshold,max_threshold,max_probability".to_string(),
+        );
+    };
 
-    Ok((start, end))
+    Red::new(
+        weight.parse().map_err(|_| "Invalid RED weight")?,
+        min_threshold
+            .parse()
+            .map_err(|_| "Invalid RED minimum threshold")?,
+        max_threshold
+            .parse()
+            .map_err(|_| "Invalid RED maximum threshold")?,
+        max_probability
+            .parse()
+            .map_err(|_| "Invalid RED maximum probability")?,
+        false,
+    )
+    .map_err(|e| e.to_string())
 }
 
 fn parse_output(s: &str) -> Result<Output, String> {
@@ -103,8 +271,46 @@ fn parse_output(s: &str) -> Result<Output, String> {
 }
 
 fn check_root_access() -> bool {
-    // Try to access a root-only file
-    fs::metadata("/etc/pf.conf").is_ok()
+    // Both pfctl/dnctl and tc need an effective uid of 0
+    process::Command::new("id")
+        .arg("-u")
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim() == "0")
+        .unwrap_or(false)
+}
+
+fn require_root_access() {
+    if !check_root_access() {
+        error!("This program must be run with root privileges");
+        process::exit(1);
+    }
+}
+
+/// Handlers of the signals asking the process to stop: SIGINT (Ctrl-C),
+/// SIGTERM and SIGHUP
+struct ShutdownSignals {
+    interrupt: Signal,
+    terminate: Signal,
+    hangup: Signal,
+}
+
+impl ShutdownSignals {
+    fn install() -> std::io::Result<Self> {
+        Ok(Self {
+            interrupt: signal(SignalKind::interrupt())?,
+            terminate: signal(SignalKind::terminate())?,
+            hangup: signal(SignalKind::hangup())?,
+        })
+    }
+
+    /// Waits for one of the signals, and returns its name
+    async fn recv(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.interrupt.recv() => "SIGINT",
+            _ = self.terminate.recv() => "SIGTERM",
+            _ = self.hangup.recv() => "SIGHUP",
+        }
+    }
 }
 
 #[tokio::main(flavor = "multi_thread")]
@@ -119,65 +325,110 @@ async fn main() {
     // Parse command line arguments
     let cli = Cli::parse();
 
-    // Check if we have root access
-    if !check_root_access() {
-        error!("This program must be run with root privileges");
-        process::exit(1);
-    }
-
     match cli.command {
         Commands::Start {
-            packet_loss,
-            latency,
-            bandwidth,
+            conditions,
             protocol,
+            family,
             src_ports,
             dst_ports,
+            src_addresses,
+            dst_addresses,
+            interfaces,
+            flow_mask,
             report_output,
+            foreground,
         } => {
+            if !cli.dry_run {
+                require_root_access();
+            }
             info!("Starting traffic shaping...");
 
             // Create traffic shaping configuration
-            let config = match TrafficConfig::new(
-                packet_loss,
-                latency,
-                bandwidth,
-                protocol,
-                src_ports.map(|(start, end)| PortRange { start, end }),
-                dst_ports.map(|(start, end)| PortRange { start, end }),
-                report_output.map_or(Output::None, |e| e),
-            ) {
-                Ok(config) => config,
+            let config = match conditions.link_conditions().and_then(|conditions| {
+                TrafficConfig::new(
+                    conditions.packet_loss,
+                    conditions.latency,
+                    conditions.max_bandwidth,
+                    protocol,
+                    None,
+                    None,
+                    report_output.map_or(Output::None, |e| e),
+                )
+                .map(|config| {
+                    config
+                        .with_inbound(conditions.clone())
+                        .with_outbound(conditions)
+                })
+            }) {
+                Ok(config) => config
+                    .with_family(family)
+                    .with_src_ports(src_ports)
+                    .with_dst_ports(dst_ports)
+                    .with_src_addresses(src_addresses)
+                    .with_dst_addresses(dst_addresses)
+                    .with_interfaces(interfaces)
+                    .with_flow_mask(flow_mask),
                 Err(e) => {
                     error!("Failed to create configuration: {}", e);
                     process::exit(1);
                 }
             };
 
+            // Installed before shaping starts, so no signal can leave it in place
+            let signals = if foreground {
+                match ShutdownSignals::install() {
+                    Ok(signals) => Some(signals),
+                    Err(e) => {
+                        error!("Failed to install signal handlers: {}", e);
+                        process::exit(1);
+                    }
+                }
+            } else {
+                None
+            };
+
             // Apply traffic shaping
-            let mut shaper = TrafficShaper::new(config);
-            if let Err(e) = shaper.enable() {
-                error!("Failed to apply traffic shaping: {}", e);
-                process::exit(1);
-            }
+            let mut shaper = if cli.dry_run {
+                TrafficShaper::dry_run(config)
+            } else {
+                TrafficShaper::new(config)
+            };
+            let shaping = match shaper.enable() {
+                Ok(shaping) => shaping,
+                Err(e) => {
+                    error!("Failed to apply traffic shaping: {}", e);
+                    process::exit(1);
+                }
+            };
 
             info!("Traffic shaping started successfully");
+
+            let Some(mut signals) = signals else {
+                // Left in place for the stop command
+                shaping.keep();
+                return;
+            };
+
+            let signal = signals.recv().await;
+            info!("Received {}, stopping traffic shaping...", signal);
+            if let Err(e) = shaping.cleanup() {
+                error!("Failed to stop traffic shaping: {}", e);
+                process::exit(1);
+            }
+            info!("Traffic shaping stopped successfully");
         }
         Commands::Stop => {
+            let mut backend = if cli.dry_run {
+                dry_run_backend()
+            } else {
+                require_root_access();
+                default_backend()
+            };
             info!("Stopping traffic shaping...");
 
-            // Create a dummy config just to use the cleanup functionality
-            let config =
-                match TrafficConfig::new(0.0, 0, 0, Protocol::Both, None, None, Output::None) {
-                    Ok(config) => config,
-                    Err(e) => {
-                        error!("Failed to create configuration: {}", e);
-                        process::exit(1);
-                    }
-                };
-
-            let shaper = TrafficShaper::new(config);
-            if let Err(e) = shaper.cleanup() {
+            // The backend undoes the changes recorded by the session of `start`
+            if let Err(e) = backend.cleanup() {
                 error!("Failed to stop traffic shaping: {}", e);
                 process::exit(1);
             }
@@ -190,11 +441,42 @@ async fn main() {
             let contents = fs::read_to_string(manifest_path).expect("failed to open manifest path");
             let manifest: Manifest =
                 from_str(contents.as_str()).expect("failed to convert contents to manifest");
+            if cli.dry_run {
+                let result = Simulation::dry_run(manifest, Instant::now())
+                    .and_then(|mut simulation| simulation.print_timeline());
+                if let Err(e) = result {
+                    error!("Failed to print the simulation: {}", e);
+                    process::exit(1);
+                }
+                return;
+            }
+
             let mut simulation = Simulation::new(manifest, Instant::now());
+            if simulation.requires_privileges() {
+                require_root_access();
+            }
+
+            let mut signals = match ShutdownSignals::install() {
+                Ok(signals) => signals,
+                Err(e) => {
+                    error!("Failed to install signal handlers: {}", e);
+                    process::exit(1);
+                }
+            };
 
-            let join = tokio::spawn(async move { simulation.start().await });
-            if let Err(e) = join.await {
-                eprintln!("error after simulation: {}", e);
+            let mut join = tokio::spawn(async move { simulation.start().await });
+            tokio::select! {
+                res = &mut join => {
+                    if let Err(e) = res {
+                        eprintln!("error after simulation: {}", e);
+                    }
+                }
+                signal = signals.recv() => {
+                    // Dropping the simulation removes its shaping
+                    info!("Received {}, stopping the simulation...", signal);
+                    join.abort();
+                    let _ = join.await;
+                }
             }
         }
     }
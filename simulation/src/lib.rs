@@ -1,12 +1,12 @@
 use std::future::Future;
 use std::pin::{pin, Pin};
 use std::task::{Context, Poll};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use pin_project::pin_project;
 use thiserror::Error;
-use tracing::{error, info};
-use ts_core::TrafficShaper;
+use tracing::info;
+use ts_core::{ApplyConfig, ShaperBackend, TrafficShaper, DEFAULT_RULE};
 
 pub mod models;
 
@@ -23,35 +23,104 @@ pub enum SimulationError {
 }
 
 impl Simulation {
+    /// Creates a simulation replaying the manifest through the backend it
+    /// names, or the native one of the platform
     pub fn new(manifest: models::Manifest, epoch: Instant) -> Self {
+        let backend = match manifest.config.backend.clone() {
+            Some(backend) => backend.into(),
+            None => ts_core::default_backend(),
+        };
+        Self::with_backend(manifest, epoch, backend)
+    }
+
+    /// Creates a simulation replaying the manifest through the given backend
+    pub fn with_backend(
+        manifest: models::Manifest,
+        epoch: Instant,
+        backend: Box<dyn ShaperBackend>,
+    ) -> Self {
         let ts_config = manifest.config.clone().into();
-        let ts = TrafficShaper::new(ts_config);
+        let ts = TrafficShaper::with_backend(ts_config, backend);
         Self {
             manifest,
             epoch,
             ts,
         }
     }
+    /// Creates a simulation printing the commands its backend would run
+    /// instead of running them
+    pub fn dry_run(
+        manifest: models::Manifest,
+        epoch: Instant,
+    ) -> Result<Self, Box<dyn std::error::Error + Sync + Send>> {
+        let backend = match &manifest.config.backend {
+            Some(backend) => backend.dry_run()?,
+            None => ts_core::dry_run_backend(),
+        };
+        Ok(Self::with_backend(manifest, epoch, backend))
+    }
+
+    /// Checks if the backend of the simulation needs root
+    pub fn requires_privileges(&self) -> bool {
+        self.ts.requires_privileges()
+    }
+
     pub async fn start(&mut self) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
         info!("starting simulation");
-        let res = self.start_inner().await;
-        info!("cleaning up");
-        if let Err(e) = self.ts.cleanup() {
-            error!("error during simulation cleanup: {}", e);
+        self.start_inner().await.map_err(|err| err.into())
+    }
+
+    /// Prints the timeline of the simulation without waiting for the events:
+    /// every `apply` with the time it happens at, each followed by the
+    /// commands of the backend. Meant for a simulation created by `dry_run`.
+    pub fn print_timeline(&mut self) -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
+        for rule in &self.manifest.rules {
+            self.ts.add_rule(&rule.name, rule.config.clone().into())?;
+        }
+
+        println!("# {:>8.3}s enable", 0.0);
+        let mut shaping = self.ts.enable()?;
+
+        let mut end = Duration::ZERO;
+        for event in &self.manifest.events {
+            let rule = event.rule.as_deref().unwrap_or(DEFAULT_RULE);
+            let config = ApplyConfig::from(event.clone());
+            println!(
+                "# {:>8.3}s apply {} {}",
+                event.time.as_secs_f64(),
+                rule,
+                serde_json::to_string(&config)?
+            );
+            shaping.apply_rule(rule, config)?;
+            end = end.max(event.time);
         }
-        res.map_err(|err| err.into())
+
+        println!("# {:>8.3}s cleanup", end.as_secs_f64());
+        shaping.cleanup()?;
+        Ok(())
     }
 
     async fn start_inner(&mut self) -> Result<(), SimulationError> {
-        self.ts
+        for rule in &self.manifest.rules {
+            self.ts
+                .add_rule(&rule.name, rule.config.clone().into())
+                .map_err(|err| SimulationError::SystemError(err.into()))?;
+        }
+
+        // The shaping is removed when the guard is dropped: once the events
+        // are replayed, on an error or a panic, or when the future is dropped
+        // because the simulation was interrupted
+        let mut shaping = self
+            .ts
             .enable()
             .map_err(|err| SimulationError::SystemError(err.into()))?;
 
         self.epoch = Instant::now();
 
-        let mut d = Driver::new(&self.manifest.events, &mut self.ts, self.epoch.clone());
+        let d = Driver::new(&self.manifest.events, &mut shaping, self.epoch);
 
         let res = pin!(d).await;
+        info!("cleaning up");
         res
     }
 }
@@ -98,8 +167,9 @@ impl<'a> Future for Driver<'a> {
                 this.events.len()
             );
 
+            let rule = event.rule.as_deref().unwrap_or(DEFAULT_RULE);
             this.traffic_shaper
-                .apply(event.clone().into())
+                .apply_rule(rule, event.clone().into())
                 .map_err(|err| SimulationError::SystemError(err.into()))?;
 
             *this.pos += 1;
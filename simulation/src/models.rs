@@ -1,56 +1,368 @@
+use std::net::SocketAddr;
 use std::time::Duration;
 
-use serde::Deserialize;
-use serde_with::{serde_as, DurationSeconds};
-use ts_core::{ApplyConfig, Output, PortRange, Protocol, TrafficConfig};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use ts_core::{
+    AddressFamily, ApplyConfig, Bandwidth, Delay, FlowMask, GilbertElliott, Interfaces, IpNetwork,
+    Jitter, LinkConditions, Output, PfBackend, PortRange, Protocol, QueueSize, Red, Reorder,
+    ShaperBackend, TcBackend, TcpProxyBackend, TrafficClass, TrafficConfig, TrafficShapingError,
+    UdpProxyBackend,
+};
 
 #[derive(Deserialize, Clone)]
 pub struct Manifest {
+    /// Configuration of the default rule and of the simulation itself
     pub config: Config,
+    /// Rules shaped alongside the default one, each with its own conditions
+    #[serde(default)]
+    pub rules: Vec<Rule>,
     pub events: Vec<Events>,
 }
 
 #[derive(Deserialize, Clone)]
-pub struct Config {
+pub struct Rule {
+    pub name: String,
+    /// `report_output` and `backend` are ignored, they are set by `config`
+    #[serde(flatten)]
+    pub config: Config,
+}
+
+/// Conditions of one direction of the traffic
+#[derive(Deserialize, Clone, Debug)]
+pub struct LinkConfig {
+    /// Independent loss, may be left out when `burst_loss` is set
+    #[serde(default)]
     pub packet_loss: f32,
-    pub latency: u32,
-    pub bandwidth: u64,
+    /// Milliseconds, or a string with a unit like `"250ms"` or `"1.2s"`
+    pub latency: Delay,
+    /// Bits per second, or a string with a unit like `"10Mbit"` or `"1.5MB/s"`
+    pub bandwidth: Bandwidth,
+    #[serde(default)]
+    pub jitter: Option<Jitter>,
+    #[serde(default)]
+    pub burst_loss: Option<GilbertElliott>,
+    #[serde(default)]
+    pub reorder: Option<Reorder>,
+    #[serde(default)]
+    pub duplicate: f32,
+    #[serde(default)]
+    pub corrupt: f32,
+    #[serde(default)]
+    pub queue: Option<QueueSize>,
+    #[serde(default)]
+    pub red: Option<Red>,
+    #[serde(default)]
+    pub ecn: bool,
+}
+
+/// Either conditions shared by both directions, given inline, or separate
+/// `inbound` and `outbound` conditions
+#[derive(Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum Conditions {
+    Asymmetric {
+        inbound: LinkConfig,
+        outbound: LinkConfig,
+    },
+    Symmetric(LinkConfig),
+}
+
+impl From<LinkConfig> for LinkConditions {
+    fn from(config: LinkConfig) -> Self {
+        LinkConditions {
+            packet_loss: config.packet_loss,
+            latency: config.latency,
+            max_bandwidth: config.bandwidth,
+            jitter: config.jitter,
+            burst_loss: config.burst_loss,
+            reorder: config.reorder,
+            duplicate: config.duplicate,
+            corrupt: config.corrupt,
+            queue: config.queue,
+            red: config.red,
+            ecn: config.ecn,
+        }
+    }
+}
+
+impl From<Conditions> for ApplyConfig {
+    fn from(conditions: Conditions) -> Self {
+        match conditions {
+            Conditions::Asymmetric { inbound, outbound } => ApplyConfig {
+                inbound: inbound.into(),
+                outbound: outbound.into(),
+            },
+            Conditions::Symmetric(config) => {
+                let conditions = LinkConditions::from(config);
+                ApplyConfig {
+                    inbound: conditions.clone(),
+                    outbound: conditions,
+                }
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct Config {
+    #[serde(flatten)]
+    pub conditions: Conditions,
+    /// `"tcp"`, `"udp"`, `"both"`, `"icmp"`, `"icmp6"`, `"any"`, a protocol
+    /// name or number
     pub protocol: Protocol,
-    pub src_ports: Option<(u16, u16)>,
-    pub dst_ports: Option<(u16, u16)>,
+    /// `"inet"`, `"inet6"` or `"both"`, the default
+    #[serde(default)]
+    pub family: AddressFamily,
+    /// Ports the traffic comes from, any if unset
+    #[serde(default)]
+    pub src_ports: Option<Ports>,
+    /// Ports the traffic goes to, any if unset
+    #[serde(default)]
+    pub dst_ports: Option<Ports>,
+    /// Hosts or CIDR blocks the traffic comes from, any if empty
+    #[serde(default)]
+    pub src_addresses: Vec<IpNetwork>,
+    /// Hosts or CIDR blocks the traffic goes to, any if empty
+    #[serde(default)]
+    pub dst_addresses: Vec<IpNetwork>,
+    /// `"all"`, `"loopback"`, `"non-loopback"` or a list of interface names
+    #[serde(default)]
+    pub interfaces: Interfaces,
+    /// `"all"` or a list of the fields telling flows apart, shared if unset
+    #[serde(default)]
+    pub flow_mask: FlowMask,
+    /// Classes sharing the conditions by their weights, the filters of the
+    /// classes replacing the ones of the config
+    #[serde(default)]
+    pub classes: Vec<ClassConfig>,
     pub report_output: Option<Output>,
+    /// Backend replaying the manifest, the native one of the platform if unset
+    pub backend: Option<Backend>,
+}
+
+/// A traffic class and its weight, from 1 to 100
+#[derive(Deserialize, Clone, Debug)]
+pub struct ClassConfig {
+    pub name: String,
+    pub weight: u32,
+    pub protocol: Protocol,
+    #[serde(default)]
+    pub src_ports: Option<Ports>,
+    #[serde(default)]
+    pub dst_ports: Option<Ports>,
+    #[serde(default)]
+    pub src_addresses: Vec<IpNetwork>,
+    #[serde(default)]
+    pub dst_addresses: Vec<IpNetwork>,
+}
+
+/// Ports as written in a manifest: a `[start, end]` pair, a single port,
+/// range or service name, or a list of them such as
+/// `[443, "5000-5100", "https"]`
+#[derive(Deserialize, Clone, Debug)]
+#[serde(try_from = "PortsSpec")]
+pub struct Ports(Vec<PortRange>);
+
+impl Ports {
+    fn into_ranges(self) -> Vec<PortRange> {
+        self.0
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PortsSpec {
+    Pair(u16, u16),
+    List(Vec<PortRange>),
+    One(PortRange),
+}
+
+impl TryFrom<PortsSpec> for Ports {
+    type Error = TrafficShapingError;
+
+    fn try_from(spec: PortsSpec) -> Result<Self, Self::Error> {
+        Ok(Self(match spec {
+            // A range, as in earlier manifests. `[443, "7777"]` lists two
+            // ports instead, and an inverted pair is refused rather than
+            // read as either.
+            PortsSpec::Pair(start, end) => vec![PortRange::new(start, end)?],
+            PortsSpec::List(ranges) => ranges,
+            PortsSpec::One(range) => vec![range],
+        }))
+    }
 }
 
-impl Into<TrafficConfig> for Config {
-    fn into(self) -> TrafficConfig {
+impl From<ClassConfig> for TrafficClass {
+    fn from(config: ClassConfig) -> Self {
+        TrafficClass {
+            name: config.name,
+            weight: config.weight,
+            protocol: config.protocol,
+            src_ports: config.src_ports.map(Ports::into_ranges).unwrap_or_default(),
+            dst_ports: config.dst_ports.map(Ports::into_ranges).unwrap_or_default(),
+            src_addresses: config.src_addresses,
+            dst_addresses: config.dst_addresses,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Backend {
+    Pf,
+    Tc,
+    UdpProxy {
+        listen: SocketAddr,
+        upstream: SocketAddr,
+    },
+    TcpProxy {
+        listen: SocketAddr,
+        upstream: SocketAddr,
+    },
+}
+
+impl From<Backend> for Box<dyn ShaperBackend> {
+    fn from(backend: Backend) -> Self {
+        match backend {
+            Backend::Pf => Box::new(PfBackend::new()),
+            Backend::Tc => Box::new(TcBackend::new()),
+            Backend::UdpProxy { listen, upstream } => {
+                Box::new(UdpProxyBackend::new(listen, upstream))
+            }
+            Backend::TcpProxy { listen, upstream } => {
+                Box::new(TcpProxyBackend::new(listen, upstream))
+            }
+        }
+    }
+}
+
+impl Backend {
+    /// Returns the backend printing the commands it would run instead of
+    /// running them. The proxies have no such mode, as they run no command.
+    pub fn dry_run(&self) -> Result<Box<dyn ShaperBackend>, TrafficShapingError> {
+        match self {
+            Backend::Pf => Ok(Box::new(PfBackend::dry_run())),
+            Backend::Tc => Ok(Box::new(TcBackend::dry_run())),
+            Backend::UdpProxy { .. } | Backend::TcpProxy { .. } => Err(
+                TrafficShapingError::Unsupported("dry run of a proxy".to_string()),
+            ),
+        }
+    }
+}
+
+impl From<Config> for TrafficConfig {
+    fn from(config: Config) -> Self {
+        let conditions = ApplyConfig::from(config.conditions);
         ts_core::TrafficConfig {
-            packet_loss: self.packet_loss,
-            latency: self.latency,
-            max_bandwidth: self.bandwidth,
-            protocol: self.protocol,
-            src_ports: self.src_ports.map(|(start, end)| PortRange { start, end }),
-            dst_ports: self.dst_ports.map(|(start, end)| PortRange { start, end }),
-            report_output: self.report_output.map_or(Output::None, |v| v),
+            inbound: conditions.inbound,
+            outbound: conditions.outbound,
+            protocol: config.protocol,
+            family: config.family,
+            src_ports: config.src_ports.map(Ports::into_ranges).unwrap_or_default(),
+            dst_ports: config.dst_ports.map(Ports::into_ranges).unwrap_or_default(),
+            src_addresses: config.src_addresses,
+            dst_addresses: config.dst_addresses,
+            interfaces: config.interfaces,
+            flow_mask: config.flow_mask,
+            classes: config.classes.into_iter().map(TrafficClass::from).collect(),
+            report_output: config.report_output.map_or(Output::None, |v| v),
         }
     }
 }
 
-#[serde_as]
 #[derive(Deserialize, Clone, Debug)]
 pub struct Events {
-    #[serde_as(as = "DurationSeconds<u64>")]
+    /// Seconds since the start, or a string with a unit like `"1.5s"`
+    #[serde(deserialize_with = "event_time")]
     pub time: Duration,
-    pub latency: u32,
-    pub bandwidth: u64,
-    pub packet_loss: f32,
+    /// Rule the event applies to, the default one if unset
+    #[serde(default)]
+    pub rule: Option<String>,
+    #[serde(flatten)]
+    pub conditions: Conditions,
 }
 
-impl Into<ApplyConfig> for Events {
-    fn into(self) -> ApplyConfig {
-        ApplyConfig {
-            packet_loss: self.packet_loss,
-            latency: self.latency,
-            max_bandwidth: self.bandwidth,
-        }
+/// Reads the time of an event, from a number of seconds or a delay with a
+/// unit. A bare number in a string is refused, as delays read those as
+/// milliseconds.
+fn event_time<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Time {
+        Seconds(u64),
+        Text(String),
+    }
+
+    match Time::deserialize(deserializer)? {
+        Time::Seconds(seconds) => Ok(Duration::from_secs(seconds)),
+        Time::Text(s) if s.trim().parse::<f64>().is_ok() => Err(D::Error::custom(format!(
+            "event time {:?} needs a unit, like \"{}s\"",
+            s,
+            s.trim()
+        ))),
+        Time::Text(s) => s
+            .parse::<Delay>()
+            .map(Delay::as_duration)
+            .map_err(D::Error::custom),
+    }
+}
+
+impl From<Events> for ApplyConfig {
+    fn from(event: Events) -> Self {
+        event.conditions.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ports(json: &str) -> Result<Vec<PortRange>, serde_json::Error> {
+        serde_json::from_str::<Ports>(json).map(Ports::into_ranges)
+    }
+
+    #[test]
+    fn two_numbers_are_a_range() {
+        assert_eq!(
+            ports("[5000, 5100]").unwrap(),
+            [PortRange::new(5000, 5100).unwrap()]
+        );
+    }
+
+    #[test]
+    fn inverted_pair_is_refused() {
+        let error = ports("[7778, 7777]").unwrap_err();
+        assert!(
+            error.to_string().contains("Invalid port range"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn lists_ports_and_ranges() {
+        assert_eq!(
+            ports(r#"[443, "7777"]"#).unwrap(),
+            [PortRange::single(443), PortRange::single(7777)]
+        );
+        assert_eq!(
+            ports(r#"[443, 3478, "5000-5100"]"#).unwrap(),
+            [
+                PortRange::single(443),
+                PortRange::single(3478),
+                PortRange::new(5000, 5100).unwrap()
+            ]
+        );
+        assert!(ports(r#"["5100-5000"]"#).is_err());
+    }
+
+    #[test]
+    fn single_port_or_range() {
+        assert_eq!(ports("53").unwrap(), [PortRange::single(53)]);
+        assert_eq!(
+            ports(r#""27000:27100""#).unwrap(),
+            [PortRange::new(27000, 27100).unwrap()]
+        );
     }
 }
@@ -0,0 +1,64 @@
+use crate::{ApplyConfig, DummynetStatus, ShapingRule, TrafficShapingError};
+
+mod pf;
+mod session;
+pub use pf::PfBackend;
+
+mod tc;
+pub use tc::TcBackend;
+
+mod proxy;
+mod udp_proxy;
+pub use udp_proxy::UdpProxyBackend;
+
+mod tcp_proxy;
+pub use tcp_proxy::TcpProxyBackend;
+
+/// A mechanism that realizes a set of `ShapingRule`s on the system.
+///
+/// `TrafficShaper` drives a backend through its whole lifecycle: `enable`
+/// once, `apply` any number of times, then `cleanup`.
+pub trait ShaperBackend: Send {
+    /// Installs the shaping described by the rules
+    fn enable(&mut self, rules: &[ShapingRule]) -> Result<(), TrafficShapingError>;
+
+    /// Updates the conditions of the named rule
+    fn apply(&mut self, rule: &str, config: &ApplyConfig) -> Result<(), TrafficShapingError>;
+
+    /// Checks if shaping installed by this backend is currently active
+    fn is_active(&self) -> Result<bool, TrafficShapingError>;
+
+    /// Checks if the backend needs root to modify the system
+    fn requires_privileges(&self) -> bool {
+        true
+    }
+
+    /// Lists the dummynet pipes and queues, for the backends shaping with them
+    fn dummynet_status(&self) -> Result<DummynetStatus, TrafficShapingError> {
+        Err(TrafficShapingError::Unsupported(
+            "dummynet status".to_string(),
+        ))
+    }
+
+    /// Removes the shaping and restores the original configuration
+    fn cleanup(&mut self) -> Result<(), TrafficShapingError>;
+}
+
+/// Returns the backend native to the current platform
+pub fn default_backend() -> Box<dyn ShaperBackend> {
+    if cfg!(target_os = "linux") {
+        Box::new(TcBackend::new())
+    } else {
+        Box::new(PfBackend::new())
+    }
+}
+
+/// Returns the backend native to the current platform, printing the
+/// commands it would run instead of running them
+pub fn dry_run_backend() -> Box<dyn ShaperBackend> {
+    if cfg!(target_os = "linux") {
+        Box::new(TcBackend::dry_run())
+    } else {
+        Box::new(PfBackend::dry_run())
+    }
+}
@@ -0,0 +1,993 @@
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+
+use tracing::{info, warn};
+
+use super::session::{ClassQueues, RulePipes, Session, SESSION_PATH};
+use super::ShaperBackend;
+use crate::commands::{DnctlCommands, PfctlCommands};
+use crate::pf_rule::{Dummynet, PfAction};
+use crate::rules::RuleGenerator;
+use crate::runner::{CommandOutput, CommandRunner, DryRunRunner, SystemRunner};
+use crate::{
+    ApplyConfig, Delay, DummynetStatus, FlowMask, LinkConditions, ShapingRule, TrafficShapingError,
+    WEIGHTS,
+};
+
+const FIRST_PIPE_NUMBER: u32 = 1;
+/// Anchor of the shaper when the main ruleset has to be hooked to it
+const ANCHOR_NAME: &str = "traffic_shaper";
+/// Anchors evaluated by the default ruleset of macOS
+const APPLE_ANCHORS: &str = "com.apple/*";
+/// Anchor of the shaper below the ones of macOS, which needs no change to
+/// the main ruleset
+const APPLE_ANCHOR_NAME: &str = "com.apple/traffic_shaper";
+
+/// Shapes traffic with pf rules sending the packets matching each rule
+/// through dummynet pipes of its own, one per direction.
+///
+/// The pf rules live in an anchor of their own, so the rules loaded by
+/// other software stay in place. The main ruleset is only changed if it
+/// evaluates no anchor the shaper can use, and the snapshot taken before
+/// is then restored on cleanup.
+///
+/// PF is enabled with a reference of its own. Cleanup only releases that
+/// reference, leaving PF enabled if it was before, or if other software
+/// holds one.
+///
+/// Every change is recorded in a session file as it is made, so cleanup
+/// from another process undoes exactly these changes: the pipes and queues
+/// of other tools are left alone. Enabling fails while another session is
+/// active, and recovers the session of a process that died before
+/// completing it.
+pub struct PfBackend {
+    runner: Arc<dyn CommandRunner>,
+    pfctl: PfctlCommands,
+    dnctl: DnctlCommands,
+    session: Session,
+    session_path: PathBuf,
+    /// Whether the commands are printed instead of run, and the session
+    /// never saved
+    dry_run: bool,
+}
+
+impl PfBackend {
+    pub fn new() -> Self {
+        Self::with_runner(Arc::new(SystemRunner))
+    }
+
+    /// Creates a backend running pfctl and dnctl through the given runner
+    pub fn with_runner(runner: Arc<dyn CommandRunner>) -> Self {
+        Self {
+            pfctl: PfctlCommands::new(runner.clone()),
+            dnctl: DnctlCommands::new(runner.clone()),
+            runner,
+            session: Session::new(),
+            session_path: PathBuf::from(SESSION_PATH),
+            dry_run: false,
+        }
+    }
+
+    /// Creates a backend printing the pfctl and dnctl commands it would run
+    /// instead of running them. It assumes no pipe is configured yet, and
+    /// only reads the session, to print what cleanup would undo.
+    pub fn dry_run() -> Self {
+        let runner = DryRunRunner::new();
+        runner.respond("pfctl -E", CommandOutput::success("Token : <token>\n"));
+        Self {
+            dry_run: true,
+            ..Self::with_runner(Arc::new(runner))
+        }
+    }
+
+    /// Keeps the session in another file than
+    /// /var/run/traffic_shaper.session
+    pub fn with_session_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.session_path = path.into();
+        self
+    }
+}
+
+impl Default for PfBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PfBackend {
+    fn pipes_of(&self, rule: &str) -> Option<RulePipes> {
+        self.session
+            .pipes
+            .iter()
+            .find(|(name, _)| name == rule)
+            .map(|(_, pipes)| pipes.clone())
+    }
+
+    /// Picks the lowest pipe number used neither by another tool nor by
+    /// another rule, and marks it as used
+    fn allocate_pipe(&self, used: &mut Vec<u32>) -> u32 {
+        let pipe = (FIRST_PIPE_NUMBER..)
+            .find(|pipe| {
+                !used.contains(pipe)
+                    && !self
+                        .session
+                        .pipes
+                        .iter()
+                        .any(|(_, p)| p.inbound == *pipe || p.outbound == *pipe)
+            })
+            .unwrap();
+        used.push(pipe);
+        pipe
+    }
+
+    /// Picks the lowest queue number used neither by another tool nor by
+    /// another class, and marks it as used
+    fn allocate_queue(&self, used: &mut Vec<u32>) -> u32 {
+        let queue = (FIRST_PIPE_NUMBER..)
+            .find(|queue| {
+                !used.contains(queue)
+                    && !self.session.pipes.iter().any(|(_, p)| {
+                        p.queues
+                            .iter()
+                            .any(|q| q.inbound == *queue || q.outbound == *queue)
+                    })
+            })
+            .unwrap();
+        used.push(queue);
+        queue
+    }
+
+    /// Fails on the conditions dummynet cannot reproduce
+    fn check_supported(conditions: &LinkConditions) -> Result<(), TrafficShapingError> {
+        let unsupported = [
+            ("jitter", conditions.jitter.is_some()),
+            ("burst loss", conditions.burst_loss.is_some()),
+            ("reordering", conditions.reorder.is_some()),
+            ("duplication", conditions.duplicate > 0.0),
+            ("corruption", conditions.corrupt > 0.0),
+            ("ECN", conditions.ecn),
+            (
+                "delays finer than a millisecond",
+                Delay::from_millis(conditions.latency.as_millis()) != conditions.latency,
+            ),
+        ];
+
+        match unsupported.iter().find(|(_, set)| *set) {
+            Some((impairment, _)) => Err(TrafficShapingError::Unsupported(format!(
+                "{} on dummynet pipes",
+                impairment
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    fn save_session(&self) -> Result<(), TrafficShapingError> {
+        if self.dry_run {
+            return Ok(());
+        }
+        self.session.save(&self.session_path)
+    }
+
+    /// Checks if a saved session was left behind: by a process that died
+    /// before completing it, or with none of its pipes left, e.g. after a
+    /// reboot or a flush by another tool
+    fn is_stale(&self, session: &Session) -> Result<bool, TrafficShapingError> {
+        if !session.complete {
+            return Ok(session.pid == process::id() || !session.is_running(&*self.runner));
+        }
+
+        let used = self.dnctl.list_pipes()?;
+        Ok(!session
+            .pipes
+            .iter()
+            .any(|(_, pipes)| used.contains(&pipes.inbound) || used.contains(&pipes.outbound)))
+    }
+
+    /// Undoes what is left of a stale session before starting a new one.
+    /// Fails if the saved session is still active.
+    fn recover_stale_session(&mut self) -> Result<(), TrafficShapingError> {
+        // A dry run cannot tell which pipes are configured
+        if self.dry_run {
+            return Ok(());
+        }
+        let Some(saved) = Session::load(&self.session_path)? else {
+            return Ok(());
+        };
+        if !self.is_stale(&saved)? {
+            return Err(TrafficShapingError::SessionActive(saved.pid));
+        }
+
+        warn!(
+            "recovering the stale session started by process {} at {}",
+            saved.pid, saved.started_at
+        );
+        self.session = saved;
+        if let Err(e) = self.undo() {
+            warn!("failed to undo the stale session: {}", e);
+        }
+        self.session = Session::new();
+        Ok(())
+    }
+
+    /// Picks the anchor of the pf rules. The anchors of macOS are used if
+    /// the main ruleset evaluates them, otherwise the main ruleset is
+    /// reloaded with a rule evaluating the anchor of the shaper.
+    fn hook_anchor(&mut self) -> Result<String, TrafficShapingError> {
+        if let Some(anchor) = &self.session.anchor {
+            return Ok(anchor.clone());
+        }
+
+        let original = self.session.original.clone().unwrap_or_default();
+        if original.has_dummynet_anchor(APPLE_ANCHORS) {
+            self.session.anchor = Some(APPLE_ANCHOR_NAME.to_string());
+            self.save_session()?;
+        } else {
+            // Saved first, so the main ruleset is restored even if the
+            // process dies right after changing it
+            self.session.anchor = Some(ANCHOR_NAME.to_string());
+            self.save_session()?;
+            let hooked = original.with_dummynet_anchor(ANCHOR_NAME);
+            self.pfctl.load_rules(&hooked.render(), None)?;
+            info!("hooked anchor {} into the main ruleset", ANCHOR_NAME);
+        }
+
+        Ok(self.session.anchor.clone().unwrap())
+    }
+
+    /// Removes the pf rules of the shaper, and restores the main ruleset if
+    /// it was changed
+    fn unhook_anchor(&mut self) -> Result<(), TrafficShapingError> {
+        if let Some(anchor) = &self.session.anchor {
+            self.pfctl.flush_anchor(anchor)?;
+            if let (ANCHOR_NAME, Some(original)) = (anchor.as_str(), &self.session.original) {
+                self.pfctl.load_rules(&original.render(), None)?;
+                info!("restored the main ruleset");
+            }
+        }
+
+        self.session.anchor = None;
+        self.session.original = None;
+        self.save_session()
+    }
+
+    /// Removes the anchors of the shaper without a session telling which
+    /// one was used, and the rule evaluating its anchor from the live ruleset
+    fn remove_anchors(&self) -> Result<(), TrafficShapingError> {
+        // Either anchor may not exist
+        for anchor in [APPLE_ANCHOR_NAME, ANCHOR_NAME] {
+            let _ = self.pfctl.flush_anchor(anchor);
+        }
+        let live = self.pfctl.snapshot()?;
+        if live.has_dummynet_anchor(ANCHOR_NAME) {
+            let unhooked = live.without_dummynet_anchor(ANCHOR_NAME);
+            self.pfctl.load_rules(&unhooked.render(), None)?;
+            info!("unhooked anchor {} from the main ruleset", ANCHOR_NAME);
+        }
+        Ok(())
+    }
+
+    /// Releases the reference taken on PF
+    fn release_pf(&mut self) -> Result<(), TrafficShapingError> {
+        let Some(token) = self.session.token.take() else {
+            return Ok(());
+        };
+
+        // A stale token, e.g. from before a reboot, must not fail every cleanup
+        self.save_session()?;
+
+        match self.pfctl.release(&token) {
+            Ok(()) => info!("pf reference {} released", token),
+            Err(e) => warn!("failed to release pf reference {}: {}", token, e),
+        }
+        Ok(())
+    }
+
+    /// Undoes the changes of the session, in the reverse order they were
+    /// made. The session is saved after each step, so an interrupted undo
+    /// resumes where it stopped.
+    fn undo(&mut self) -> Result<(), TrafficShapingError> {
+        // Only the pipes and queues of the session that still exist are
+        // deleted, the ones of other tools stay in place
+        let status = self.dnctl.show()?;
+        let used_pipes: Vec<u32> = status.pipes.iter().map(|pipe| pipe.number).collect();
+        let used_queues: Vec<u32> = status.queues.iter().map(|queue| queue.number).collect();
+
+        let mut pipes = Vec::new();
+        let mut queues = Vec::new();
+        for (_, rule_pipes) in &self.session.pipes {
+            pipes.extend([rule_pipes.inbound, rule_pipes.outbound]);
+            for class_queues in &rule_pipes.queues {
+                queues.extend([class_queues.inbound, class_queues.outbound]);
+            }
+        }
+        if !self.dry_run {
+            pipes.retain(|pipe| used_pipes.contains(pipe));
+            queues.retain(|queue| used_queues.contains(queue));
+        }
+
+        self.dnctl.delete_queues(&queues)?;
+        self.dnctl.delete_pipes(&pipes)?;
+        self.session.pipes.clear();
+        self.save_session()?;
+
+        // Remove the pf rules, leaving the rules of other software in place
+        self.unhook_anchor()?;
+
+        // Release the reference on PF, which disables it if no other exists
+        self.release_pf()?;
+
+        if !self.dry_run {
+            Session::remove(&self.session_path)?;
+        }
+        info!(
+            "undid the session started by process {} at {}",
+            self.session.pid, self.session.started_at
+        );
+        Ok(())
+    }
+
+    fn configure_pipe(
+        &self,
+        pipe: u32,
+        conditions: &LinkConditions,
+        mask: &FlowMask,
+    ) -> Result<(), TrafficShapingError> {
+        Self::check_supported(conditions)?;
+        self.dnctl.configure_pipe(pipe, conditions, mask)
+    }
+
+    fn configure_pipes(
+        &self,
+        pipes: &RulePipes,
+        config: &ApplyConfig,
+    ) -> Result<(), TrafficShapingError> {
+        // dnctl resets the mask of a pipe configured without one. The queues
+        // of the classes tell flows apart instead of the pipes if there are any.
+        let pipe_mask = if pipes.queues.is_empty() {
+            pipes.mask
+        } else {
+            FlowMask::default()
+        };
+        self.configure_pipe(pipes.inbound, &config.inbound, &pipe_mask.reversed())?;
+        self.configure_pipe(pipes.outbound, &config.outbound, &pipe_mask)?;
+
+        for queues in &pipes.queues {
+            self.dnctl.configure_queue(
+                queues.inbound,
+                pipes.inbound,
+                queues.weight,
+                &config.inbound,
+                &pipes.mask.reversed(),
+            )?;
+            self.dnctl.configure_queue(
+                queues.outbound,
+                pipes.outbound,
+                queues.weight,
+                &config.outbound,
+                &pipes.mask,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl ShaperBackend for PfBackend {
+    fn enable(&mut self, rules: &[ShapingRule]) -> Result<(), TrafficShapingError> {
+        // Refuse unsupported conditions and invalid weights before touching
+        // anything. Classes built without `TrafficClass::new` are unchecked.
+        for rule in rules {
+            Self::check_supported(&rule.config.inbound)?;
+            Self::check_supported(&rule.config.outbound)?;
+            if let Some(class) = rule
+                .config
+                .classes
+                .iter()
+                .find(|class| !WEIGHTS.contains(&class.weight))
+            {
+                return Err(TrafficShapingError::InvalidWeight(class.weight));
+            }
+        }
+
+        // Start a session, unless this backend already has one
+        if self.session.is_empty() {
+            self.recover_stale_session()?;
+            self.session = Session::new();
+
+            // Snapshot the live ruleset, which may differ from /etc/pf.conf
+            self.session.original = Some(self.pfctl.snapshot()?);
+        }
+        self.session.complete = false;
+        self.save_session()?;
+
+        // Step 1: Take a reference on PF, enabling it if not already enabled
+        if self.session.token.is_none() {
+            let token = self.pfctl.enable()?;
+            info!("pf enabled with token {}", token);
+            self.session.token = Some(token);
+            self.save_session()?;
+        }
+
+        // Step 2: Configure an inbound and an outbound dummynet pipe per rule,
+        // fed by a queue per class and direction if the rule has classes.
+        // They are allocated if the rule doesn't have them yet, or updated if it does
+        let mut used = self.dnctl.list_pipes()?;
+        let mut used_queues = if rules.iter().any(|rule| !rule.config.classes.is_empty()) {
+            self.dnctl.list_queues()?
+        } else {
+            Vec::new()
+        };
+        let mut reload_rules = false;
+        for rule in rules {
+            let classes = &rule.config.classes;
+            let mut pipes = match self.pipes_of(&rule.name) {
+                Some(pipes) => pipes,
+                None => {
+                    reload_rules = true;
+                    RulePipes {
+                        inbound: self.allocate_pipe(&mut used),
+                        outbound: self.allocate_pipe(&mut used),
+                        mask: rule.config.flow_mask,
+                        queues: Vec::new(),
+                        config: ApplyConfig::from(&rule.config),
+                    }
+                }
+            };
+            pipes.mask = rule.config.flow_mask;
+            pipes.config = ApplyConfig::from(&rule.config);
+
+            // Classes keep their queues, only added classes get new ones and
+            // the queues of removed classes are deleted
+            if pipes.queues.len() != classes.len() {
+                reload_rules = true;
+            }
+            if pipes.queues.len() > classes.len() {
+                let removed: Vec<u32> = pipes.queues[classes.len()..]
+                    .iter()
+                    .flat_map(|queues| [queues.inbound, queues.outbound])
+                    .collect();
+                self.dnctl.delete_queues(&removed)?;
+                pipes.queues.truncate(classes.len());
+            }
+            for class in &classes[pipes.queues.len()..] {
+                pipes.queues.push(ClassQueues {
+                    inbound: self.allocate_queue(&mut used_queues),
+                    outbound: self.allocate_queue(&mut used_queues),
+                    weight: class.weight,
+                });
+            }
+            for (queues, class) in pipes.queues.iter_mut().zip(classes) {
+                queues.weight = class.weight;
+            }
+
+            // Saved before configuring, so the pipes are deleted even if the
+            // process dies halfway
+            match self
+                .session
+                .pipes
+                .iter_mut()
+                .find(|(name, _)| *name == rule.name)
+            {
+                Some((_, existing)) => *existing = pipes.clone(),
+                None => self.session.pipes.push((rule.name.clone(), pipes.clone())),
+            }
+            self.save_session()?;
+
+            self.configure_pipes(&pipes, &pipes.config)?;
+            info!(
+                "configured pipes {} (in) and {} (out) for rule {}",
+                pipes.inbound, pipes.outbound, rule.name
+            );
+            for (queues, class) in pipes.queues.iter().zip(classes) {
+                info!(
+                    "configured queues {} (in) and {} (out) with weight {} for class {}",
+                    queues.inbound, queues.outbound, queues.weight, class.name
+                );
+            }
+        }
+
+        // Step 3: Generate and load PF rules into the anchor only if pipes
+        // or queues were allocated
+        if reload_rules {
+            let anchor = self.hook_anchor()?;
+
+            let mut pf_rules = String::new();
+            for rule in rules {
+                let pipes = self.pipes_of(&rule.name).unwrap();
+                if pipes.queues.is_empty() {
+                    pf_rules.push_str(&RuleGenerator::generate_pf_rules(
+                        &rule.config,
+                        Dummynet::Pipe(pipes.inbound),
+                        Dummynet::Pipe(pipes.outbound),
+                    )?);
+                }
+                for (queues, class) in pipes.queues.iter().zip(&rule.config.classes) {
+                    pf_rules.push_str(&RuleGenerator::generate_pf_rules(
+                        &class.filter(&rule.config),
+                        Dummynet::Queue(queues.inbound),
+                        Dummynet::Queue(queues.outbound),
+                    )?);
+                }
+            }
+            self.pfctl.load_rules(&pf_rules, Some(&anchor))?;
+            info!("loaded pf rules into anchor {}", anchor);
+        }
+
+        self.session.complete = true;
+        self.save_session()
+    }
+
+    fn apply(&mut self, rule: &str, config: &ApplyConfig) -> Result<(), TrafficShapingError> {
+        let pipes = self
+            .pipes_of(rule)
+            .ok_or_else(|| TrafficShapingError::UnknownRule(rule.to_string()))?;
+
+        self.configure_pipes(&pipes, config)?;
+        if let Some((_, pipes)) = self.session.pipes.iter_mut().find(|(name, _)| name == rule) {
+            pipes.config = config.clone();
+        }
+        self.save_session()
+    }
+
+    /// Checks that pipes of the session are configured, and that the rules
+    /// of its anchor still send traffic through them
+    fn is_active(&self) -> Result<bool, TrafficShapingError> {
+        let Some(anchor) = &self.session.anchor else {
+            return Ok(false);
+        };
+
+        let used = self.dnctl.list_pipes()?;
+        let mut targets = Vec::new();
+        for (_, pipes) in &self.session.pipes {
+            for pipe in [pipes.inbound, pipes.outbound] {
+                if used.contains(&pipe) {
+                    targets.push(Dummynet::Pipe(pipe));
+                }
+            }
+            for queues in &pipes.queues {
+                targets.extend([
+                    Dummynet::Queue(queues.inbound),
+                    Dummynet::Queue(queues.outbound),
+                ]);
+            }
+        }
+        if !targets
+            .iter()
+            .any(|target| matches!(target, Dummynet::Pipe(_)))
+        {
+            return Ok(false);
+        }
+
+        Ok(self.pfctl.anchor_rules(anchor)?.iter().any(
+            |rule| matches!(rule.action, PfAction::Dummynet(target) if targets.contains(&target)),
+        ))
+    }
+
+    fn requires_privileges(&self) -> bool {
+        !self.dry_run
+    }
+
+    fn dummynet_status(&self) -> Result<DummynetStatus, TrafficShapingError> {
+        self.dnctl.show()
+    }
+
+    fn cleanup(&mut self) -> Result<(), TrafficShapingError> {
+        // In another process than the one that enabled shaping, undo the
+        // changes of the saved session
+        if self.session.is_empty() {
+            match Session::load(&self.session_path)? {
+                Some(saved) => self.session = saved,
+                None => {
+                    warn!(
+                        "no session in {}, only removing the anchors of the shaper",
+                        self.session_path.display()
+                    );
+                    return self.remove_anchors();
+                }
+            }
+        }
+
+        self.undo()?;
+        self.session = Session::new();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::FakeRunner;
+    use crate::{Bandwidth, Output, PortRange, Protocol, TrafficClass, TrafficConfig};
+
+    fn rule(name: &str) -> ShapingRule {
+        ShapingRule {
+            name: name.to_string(),
+            config: TrafficConfig::new(
+                1.0,
+                Delay::from_millis(50),
+                Bandwidth::from_bps(1_000_000),
+                Protocol::Udp,
+                None,
+                Some(PortRange::single(5000)),
+                Output::None,
+            )
+            .unwrap(),
+        }
+    }
+
+    fn session_path(test: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "traffic_shaper-pf-{}-{}.session",
+            test,
+            process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    /// Returns the standard input of the rules loaded so far, and the
+    /// anchor they were loaded into
+    fn loaded_rules(fake: &FakeRunner) -> Vec<(String, String)> {
+        fake.invocations()
+            .into_iter()
+            .filter_map(|invocation| {
+                let stdin = invocation.stdin.clone()?;
+                Some((invocation.to_string(), stdin))
+            })
+            .collect()
+    }
+
+    const SNAPSHOT_LINES: [&str; 7] = [
+        "pfctl -v -s Interfaces",
+        "pfctl -s timeouts",
+        "pfctl -s memory",
+        "pfctl -s Tables",
+        "pfctl -s nat",
+        "pfctl -s dummynet",
+        "pfctl -s rules",
+    ];
+
+    #[test]
+    fn enable_apply_cleanup_in_the_apple_anchor() {
+        let fake = Arc::new(FakeRunner::new());
+        let path = session_path("enable_apply_cleanup_in_the_apple_anchor");
+        fake.respond(
+            "pfctl -s dummynet",
+            CommandOutput::success("dummynet-anchor \"com.apple/*\" all\n"),
+        );
+        fake.respond("pfctl -E", CommandOutput::success("Token : 42\n"));
+        // Pipe 1 belongs to another tool, and is listed with the pipes of
+        // the session on cleanup
+        let other = "00001: unlimited 0 ms 50 sl. 0 queues (1 buckets) droptail\n";
+        fake.respond("dnctl show", CommandOutput::success(other));
+        fake.respond(
+            "dnctl show",
+            CommandOutput::success(&format!(
+                "{}00002: unlimited 10 ms 50 sl. 0 queues (1 buckets) droptail\n\
+                 00003: unlimited 10 ms 50 sl. 0 queues (1 buckets) droptail\n",
+                other
+            )),
+        );
+        let mut backend = PfBackend::with_runner(fake.clone()).with_session_path(&path);
+
+        backend.enable(&[rule("default")]).unwrap();
+        let mut lines = SNAPSHOT_LINES.to_vec();
+        lines.extend([
+            "pfctl -E",
+            "dnctl show",
+            "dnctl pipe 2 config bw 1000000bit/s delay 50ms plr 0.01",
+            "dnctl pipe 3 config bw 1000000bit/s delay 50ms plr 0.01",
+            "pfctl -a com.apple/traffic_shaper -f -",
+        ]);
+        assert_eq!(fake.command_lines(), lines);
+        assert_eq!(
+            loaded_rules(&fake),
+            [(
+                "pfctl -a com.apple/traffic_shaper -f -".to_string(),
+                "dummynet in quick on ! lo0 proto udp from any to port 5000 pipe 2\n\
+                 dummynet out quick proto udp from any to port 5000 pipe 3\n"
+                    .to_string()
+            )]
+        );
+        assert!(Session::load(&path).unwrap().unwrap().complete);
+
+        fake.clear();
+        let config = ApplyConfig::symmetric(0.0, Delay::from_millis(10), Bandwidth::UNLIMITED);
+        backend.apply("default", &config).unwrap();
+        assert_eq!(
+            fake.command_lines(),
+            [
+                "dnctl pipe 2 config bw 0bit/s delay 10ms plr 0",
+                "dnctl pipe 3 config bw 0bit/s delay 10ms plr 0",
+            ]
+        );
+
+        // Only the pipes of the session are deleted, and the anchor of
+        // macOS is flushed without touching the main ruleset
+        fake.clear();
+        backend.cleanup().unwrap();
+        assert_eq!(
+            fake.command_lines(),
+            [
+                "dnctl show",
+                "dnctl pipe delete 2 3",
+                "pfctl -a com.apple/traffic_shaper -F all",
+                "pfctl -X 42",
+            ]
+        );
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn hooks_and_restores_the_main_ruleset() {
+        let fake = Arc::new(FakeRunner::new());
+        let path = session_path("hooks_and_restores_the_main_ruleset");
+        fake.respond("pfctl -s rules", CommandOutput::success("pass out all\n"));
+        fake.respond("pfctl -E", CommandOutput::success("Token : 42\n"));
+        let mut backend = PfBackend::with_runner(fake.clone()).with_session_path(&path);
+
+        backend.enable(&[rule("default")]).unwrap();
+        let mut lines = SNAPSHOT_LINES.to_vec();
+        lines.extend([
+            "pfctl -E",
+            "dnctl show",
+            "dnctl pipe 1 config bw 1000000bit/s delay 50ms plr 0.01",
+            "dnctl pipe 2 config bw 1000000bit/s delay 50ms plr 0.01",
+            "pfctl -f -",
+            "pfctl -a traffic_shaper -f -",
+        ]);
+        assert_eq!(fake.command_lines(), lines);
+        assert_eq!(
+            loaded_rules(&fake),
+            [
+                (
+                    "pfctl -f -".to_string(),
+                    "dummynet-anchor \"traffic_shaper\" all\npass out all\n".to_string()
+                ),
+                (
+                    "pfctl -a traffic_shaper -f -".to_string(),
+                    "dummynet in quick on ! lo0 proto udp from any to port 5000 pipe 1\n\
+                     dummynet out quick proto udp from any to port 5000 pipe 2\n"
+                        .to_string()
+                ),
+            ]
+        );
+
+        // Cleanup from another process reads the session
+        fake.clear();
+        fake.respond(
+            "dnctl show",
+            CommandOutput::success(
+                "00001: unlimited 0 ms 50 sl. 0 queues (1 buckets) droptail\n\
+                 00002: unlimited 0 ms 50 sl. 0 queues (1 buckets) droptail\n",
+            ),
+        );
+        let mut other = PfBackend::with_runner(fake.clone()).with_session_path(&path);
+        other.cleanup().unwrap();
+        assert_eq!(
+            fake.command_lines(),
+            [
+                "dnctl show",
+                "dnctl pipe delete 1 2",
+                "pfctl -a traffic_shaper -F all",
+                "pfctl -f -",
+                "pfctl -X 42",
+            ]
+        );
+        assert_eq!(
+            loaded_rules(&fake),
+            [("pfctl -f -".to_string(), "pass out all\n".to_string())]
+        );
+        assert!(!path.exists());
+    }
+
+    fn classes(weights: &[u32]) -> Vec<TrafficClass> {
+        weights
+            .iter()
+            .enumerate()
+            .map(|(idx, weight)| TrafficClass {
+                name: format!("class{}", idx),
+                weight: *weight,
+                protocol: Protocol::Tcp,
+                src_ports: Vec::new(),
+                dst_ports: vec![PortRange::single(8000 + idx as u16)],
+                src_addresses: Vec::new(),
+                dst_addresses: Vec::new(),
+            })
+            .collect()
+    }
+
+    fn classified_rule(weights: &[u32]) -> ShapingRule {
+        let mut rule = rule("default");
+        rule.config.classes = classes(weights);
+        rule
+    }
+
+    #[test]
+    fn allocates_and_deletes_the_queues_of_classes() {
+        let fake = Arc::new(FakeRunner::new());
+        let path = session_path("allocates_and_deletes_the_queues_of_classes");
+        fake.respond("pfctl -E", CommandOutput::success("Token : 42\n"));
+        let mut backend = PfBackend::with_runner(fake.clone()).with_session_path(&path);
+
+        // A weight out of range is refused before anything runs
+        assert!(matches!(
+            backend.enable(&[classified_rule(&[30, 0])]),
+            Err(TrafficShapingError::InvalidWeight(0))
+        ));
+        assert!(fake.command_lines().is_empty());
+
+        // Every class gets a queue per direction, feeding the pipes of the rule
+        backend.enable(&[classified_rule(&[30, 70])]).unwrap();
+        let mut lines = SNAPSHOT_LINES.to_vec();
+        lines.extend([
+            "pfctl -E",
+            "dnctl show",
+            "dnctl show",
+            "dnctl pipe 1 config bw 1000000bit/s delay 50ms plr 0.01",
+            "dnctl pipe 2 config bw 1000000bit/s delay 50ms plr 0.01",
+            "dnctl queue 1 config pipe 1 weight 30",
+            "dnctl queue 2 config pipe 2 weight 30",
+            "dnctl queue 3 config pipe 1 weight 70",
+            "dnctl queue 4 config pipe 2 weight 70",
+            "pfctl -f -",
+            "pfctl -a traffic_shaper -f -",
+        ]);
+        assert_eq!(fake.command_lines(), lines);
+        assert_eq!(
+            loaded_rules(&fake)[1].1,
+            "dummynet in quick on ! lo0 proto tcp from any to port 8000 queue 1\n\
+             dummynet out quick proto tcp from any to port 8000 queue 2\n\
+             dummynet in quick on ! lo0 proto tcp from any to port 8001 queue 3\n\
+             dummynet out quick proto tcp from any to port 8001 queue 4\n"
+        );
+
+        // A removed class deletes its queues before the rules are reloaded
+        fake.clear();
+        backend.enable(&[classified_rule(&[50])]).unwrap();
+        assert_eq!(
+            fake.command_lines(),
+            [
+                "dnctl show",
+                "dnctl show",
+                "dnctl queue delete 3 4",
+                "dnctl pipe 1 config bw 1000000bit/s delay 50ms plr 0.01",
+                "dnctl pipe 2 config bw 1000000bit/s delay 50ms plr 0.01",
+                "dnctl queue 1 config pipe 1 weight 50",
+                "dnctl queue 2 config pipe 2 weight 50",
+                "pfctl -a traffic_shaper -f -",
+            ]
+        );
+        let session = Session::load(&path).unwrap().unwrap();
+        assert_eq!(session.pipes[0].1.queues.len(), 1);
+
+        backend.cleanup().unwrap();
+    }
+
+    #[test]
+    fn cleanup_fails_on_an_unreadable_session() {
+        let fake = Arc::new(FakeRunner::new());
+        let path = session_path("cleanup_fails_on_an_unreadable_session");
+        std::fs::write(&path, "{\"pid\": ").unwrap();
+        let mut backend = PfBackend::with_runner(fake.clone()).with_session_path(&path);
+
+        // Nothing is undone, and the session is kept aside for the user
+        assert!(matches!(
+            backend.cleanup(),
+            Err(TrafficShapingError::UnreadableSession { .. })
+        ));
+        assert!(fake.command_lines().is_empty());
+        assert!(!path.exists());
+        let mut aside = path.into_os_string();
+        aside.push(".unreadable");
+        assert_eq!(std::fs::read_to_string(&aside).unwrap(), "{\"pid\": ");
+        std::fs::remove_file(&aside).unwrap();
+    }
+
+    #[test]
+    fn refuses_delays_dnctl_would_round() {
+        let fake = Arc::new(FakeRunner::new());
+        let path = session_path("refuses_delays_dnctl_would_round");
+        let mut backend = PfBackend::with_runner(fake.clone()).with_session_path(&path);
+
+        // dnctl takes whole milliseconds, and would configure 500us as 0ms
+        let mut submillisecond = rule("default");
+        submillisecond.config.outbound.latency = "500us".parse().unwrap();
+        assert!(matches!(
+            backend.enable(&[submillisecond]),
+            Err(TrafficShapingError::Unsupported(_))
+        ));
+        assert!(fake.command_lines().is_empty());
+
+        let mut whole = rule("default");
+        whole.config.outbound.latency = "1.5s".parse().unwrap();
+        fake.respond("pfctl -E", CommandOutput::success("Token : 42\n"));
+        backend.enable(&[whole]).unwrap();
+        assert!(fake
+            .command_lines()
+            .contains(&"dnctl pipe 2 config bw 1000000bit/s delay 1500ms plr 0.01".to_string()));
+
+        let config = ApplyConfig::symmetric(0.0, "10.25ms".parse().unwrap(), Bandwidth::UNLIMITED);
+        assert!(matches!(
+            backend.apply("default", &config),
+            Err(TrafficShapingError::Unsupported(_))
+        ));
+
+        backend.cleanup().unwrap();
+    }
+
+    /// Returns the releases of PF references run so far
+    fn releases(fake: &FakeRunner) -> Vec<String> {
+        fake.command_lines()
+            .into_iter()
+            .filter(|line| line.starts_with("pfctl -X") || line.starts_with("pfctl -d"))
+            .collect()
+    }
+
+    #[test]
+    fn releases_only_the_tokens_it_took() {
+        let fake = Arc::new(FakeRunner::new());
+        let path = session_path("releases_only_the_tokens_it_took");
+        // pfctl prints the token on stderr, among other messages
+        fake.respond(
+            "pfctl -E",
+            CommandOutput {
+                status: 0,
+                stdout: String::new(),
+                stderr: "No ALTQ support in kernel\npf enabled\nToken : 8\n".to_string(),
+            },
+        );
+
+        // A process that died halfway left its token in the session
+        let stale = Session {
+            token: Some("7".to_string()),
+            ..Session::new()
+        };
+        stale.save(&path).unwrap();
+
+        let mut backend = PfBackend::with_runner(fake.clone()).with_session_path(&path);
+        backend.enable(&[rule("default")]).unwrap();
+        assert_eq!(releases(&fake), ["pfctl -X 7"]);
+
+        // Updating the rules keeps the reference already taken
+        backend.enable(&[rule("default")]).unwrap();
+        let enables = fake
+            .command_lines()
+            .into_iter()
+            .filter(|line| line == "pfctl -E")
+            .count();
+        assert_eq!(enables, 1);
+
+        // PF is never disabled outright, and a failed release is no reason
+        // to keep the session
+        fake.respond(
+            "pfctl -X",
+            CommandOutput::failure(1, "pfctl: Invalid argument"),
+        );
+        backend.cleanup().unwrap();
+        assert_eq!(releases(&fake), ["pfctl -X 7", "pfctl -X 8"]);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn enable_refuses_an_active_session() {
+        let fake = Arc::new(FakeRunner::new());
+        let path = session_path("enable_refuses_an_active_session");
+        fake.respond("pfctl -E", CommandOutput::success("Token : 42\n"));
+        let mut backend = PfBackend::with_runner(fake.clone()).with_session_path(&path);
+        backend.enable(&[rule("default")]).unwrap();
+
+        fake.respond(
+            "dnctl show",
+            CommandOutput::success("00001: unlimited 0 ms 50 sl. 0 queues (1 buckets) droptail\n"),
+        );
+        let mut other = PfBackend::with_runner(fake.clone()).with_session_path(&path);
+        assert!(matches!(
+            other.enable(&[rule("default")]),
+            Err(TrafficShapingError::SessionActive(_))
+        ));
+
+        backend.cleanup().unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,589 @@
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rand_distr::{Distribution, Pareto, StandardNormal};
+
+use crate::{
+    ApplyConfig, DelayDistribution, Direction, FlowMask, LinkConditions, QueueSize, ShapingRule,
+    TrafficShapingError,
+};
+
+/// How long blocking socket calls of the proxies wait before checking if
+/// they were asked to stop
+pub(crate) const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The lower bound of the TCP retransmission timeout of common stacks
+const MIN_RTO: Duration = Duration::from_millis(200);
+
+/// Shape of the Pareto distribution of delays, with a heavy tail but a
+/// finite variance, and its mean and standard deviation with a scale of 1
+const PARETO_SHAPE: f64 = 3.0;
+const PARETO_MEAN: f64 = 1.5;
+const PARETO_STD_DEV: f64 = 0.866;
+
+/// Schedules an item for delivery at the given time
+pub(crate) type Scheduler<T> = Sender<(Instant, T)>;
+
+/// Conditions of a proxy, updated by `apply` while its threads are running
+pub(crate) type SharedConditions = Arc<RwLock<ApplyConfig>>;
+
+/// Returns the only rule a proxy can shape, as it relays a single flow
+pub(crate) fn single_rule(rules: &[ShapingRule]) -> Result<&ShapingRule, TrafficShapingError> {
+    match rules {
+        [rule] if !rule.config.classes.is_empty() => Err(TrafficShapingError::Unsupported(
+            "traffic classes on a proxy".to_string(),
+        )),
+        [rule] => Ok(rule),
+        _ => Err(TrafficShapingError::Unsupported(
+            "more than one rule on a proxy".to_string(),
+        )),
+    }
+}
+
+/// One direction of an impaired link. Traffic from the client to upstream
+/// is outbound, and traffic back to the client inbound.
+///
+/// Packets are serialized one after the other at the configured bandwidth,
+/// so a burst queues up like it would in front of a dummynet pipe, then
+/// travel for the configured latency, varied by the jitter. A full queue
+/// drops packets from its tail, or earlier with RED.
+pub(crate) struct Link {
+    conditions: SharedConditions,
+    direction: Direction,
+    busy_until: Instant,
+    /// When each queued packet is fully sent, and its size
+    backlog: VecDeque<(Instant, usize)>,
+    /// Average length of the queue for RED
+    average_queue: f64,
+    /// Jitter of the previous packet, in deviations from the latency
+    last_jitter: f64,
+    /// State of the burst loss model
+    bad_state: bool,
+    /// Packets sent since the last one sent early
+    since_reorder: u32,
+    /// Random draw of the previous reordering decision
+    last_reorder: f32,
+    rng: StdRng,
+}
+
+impl Link {
+    pub fn new(conditions: SharedConditions, direction: Direction) -> Self {
+        Self {
+            conditions,
+            direction,
+            busy_until: Instant::now(),
+            backlog: VecDeque::new(),
+            average_queue: 0.0,
+            last_jitter: 0.0,
+            bad_state: false,
+            since_reorder: 0,
+            last_reorder: 0.0,
+            rng: StdRng::from_entropy(),
+        }
+    }
+
+    /// Makes the random draws of the link repeatable
+    #[cfg(test)]
+    fn with_seed(mut self, seed: u64) -> Self {
+        self.rng = StdRng::seed_from_u64(seed);
+        self
+    }
+
+    /// Returns the conditions the link currently applies
+    pub fn conditions(&self) -> LinkConditions {
+        self.conditions
+            .read()
+            .unwrap()
+            .conditions(self.direction)
+            .clone()
+    }
+
+    /// Returns the copies of a datagram sent now that reach the other end,
+    /// and when: none if it is lost, two if it is duplicated. Copies may be
+    /// corrupted, and may overtake earlier datagrams.
+    pub fn transmit(&mut self, payload: &[u8]) -> Vec<(Instant, Vec<u8>)> {
+        let conditions = self
+            .conditions
+            .read()
+            .unwrap()
+            .conditions(self.direction)
+            .clone();
+
+        if self.is_dropped(payload.len(), &conditions) || self.is_lost(&conditions) {
+            return Vec::new();
+        }
+
+        let deliver_at = if self.is_reordered(&conditions) {
+            self.serialize(payload.len(), &conditions)
+        } else {
+            self.schedule(payload.len(), &conditions)
+        };
+
+        let mut copies = vec![(deliver_at, payload.to_vec())];
+        if self.chance(conditions.duplicate) {
+            copies.push((deliver_at, payload.to_vec()));
+        }
+        for (_, copy) in &mut copies {
+            if self.chance(conditions.corrupt) && !copy.is_empty() {
+                let bit = self.rng.gen_range(0..copy.len() * 8);
+                copy[bit / 8] ^= 1 << (bit % 8);
+            }
+        }
+
+        copies
+    }
+
+    /// Returns when a chunk of a stream of `len` bytes sent now reaches the
+    /// other end. A lost chunk is delivered late instead of being dropped,
+    /// the way a reliable transport recovers it by retransmission, and the
+    /// link stalls until then, so later chunks are held back as well.
+    pub fn transmit_reliably(&mut self, len: usize) -> Instant {
+        let config = self.conditions.read().unwrap().clone();
+        let conditions = config.conditions(self.direction);
+        let deliver_at = self.schedule(len, conditions);
+
+        if self.is_lost(conditions) {
+            // The sender notices the loss after a round trip over both directions
+            let rtt = config.inbound.latency.as_duration() + config.outbound.latency.as_duration();
+            let rto = rtt.max(MIN_RTO);
+            self.busy_until += rto;
+            return deliver_at + rto;
+        }
+
+        deliver_at
+    }
+
+    /// Decides if the queue drops the next packet of `len` bytes, when it is
+    /// full or, with RED, as it fills up
+    fn is_dropped(&mut self, len: usize, conditions: &LinkConditions) -> bool {
+        self.prune_backlog();
+
+        let length = match conditions.queue {
+            Some(QueueSize::Bytes(_)) => self.backlog.iter().map(|(_, len)| *len as u64).sum(),
+            _ => self.backlog.len() as u64,
+        };
+        let full = match conditions.queue {
+            Some(QueueSize::Slots(slots)) => length >= slots as u64,
+            Some(QueueSize::Bytes(bytes)) => length + len as u64 > bytes,
+            None => false,
+        };
+
+        full || self.is_dropped_early(length, conditions)
+    }
+
+    /// Decides if RED drops the next packet, given the current length of the
+    /// queue in the unit of its size
+    fn is_dropped_early(&mut self, length: u64, conditions: &LinkConditions) -> bool {
+        let Some(red) = &conditions.red else {
+            return false;
+        };
+
+        let weight = red.weight as f64;
+        self.average_queue = (1.0 - weight) * self.average_queue + weight * length as f64;
+
+        let min = red.min_threshold as f64;
+        let max = red.max_threshold as f64;
+        let max_probability = red.max_probability as f64;
+        let probability = if self.average_queue < min {
+            0.0
+        } else if self.average_queue < max {
+            max_probability * (self.average_queue - min) / (max - min)
+        } else if red.gentle && self.average_queue < 2.0 * max {
+            max_probability + (1.0 - max_probability) * (self.average_queue - max) / max
+        } else {
+            1.0
+        };
+
+        self.rng.gen::<f64>() < probability
+    }
+
+    /// Forgets the packets that have left the queue
+    fn prune_backlog(&mut self) {
+        let now = Instant::now();
+        while self
+            .backlog
+            .front()
+            .is_some_and(|(sent_at, _)| *sent_at <= now)
+        {
+            self.backlog.pop_front();
+        }
+    }
+
+    /// Decides if the next packet is lost
+    fn is_lost(&mut self, conditions: &LinkConditions) -> bool {
+        let Some(model) = &conditions.burst_loss else {
+            return self.chance(conditions.packet_loss);
+        };
+
+        self.bad_state = if self.bad_state {
+            !self.chance(model.to_good)
+        } else {
+            self.chance(model.to_bad)
+        };
+        self.chance(if self.bad_state {
+            model.bad_loss
+        } else {
+            model.good_loss
+        })
+    }
+
+    /// Decides if the next packet is sent without latency, the way netem
+    /// reorders: only every `gap`th packet is a candidate
+    fn is_reordered(&mut self, conditions: &LinkConditions) -> bool {
+        let Some(reorder) = &conditions.reorder else {
+            return false;
+        };
+
+        if self.since_reorder + 1 < reorder.gap {
+            self.since_reorder += 1;
+            return false;
+        }
+
+        let correlation = reorder.correlation / 100.0;
+        self.last_reorder =
+            correlation * self.last_reorder + (1.0 - correlation) * self.rng.gen::<f32>();
+        if self.last_reorder * 100.0 < reorder.probability {
+            self.since_reorder = 0;
+            true
+        } else {
+            self.since_reorder += 1;
+            false
+        }
+    }
+
+    fn schedule(&mut self, len: usize, conditions: &LinkConditions) -> Instant {
+        self.serialize(len, conditions) + self.delay(conditions)
+    }
+
+    /// Returns when a packet of `len` bytes is fully sent, after the ones
+    /// queued before it
+    fn serialize(&mut self, len: usize, conditions: &LinkConditions) -> Instant {
+        // A bandwidth of 0 means unlimited, as for dummynet
+        let serialization = match conditions.max_bandwidth.bps() {
+            0 => Duration::ZERO,
+            bw => Duration::from_secs_f64(len as f64 * 8.0 / bw as f64),
+        };
+
+        self.busy_until = self.busy_until.max(Instant::now()) + serialization;
+        self.prune_backlog();
+        self.backlog.push_back((self.busy_until, len));
+        self.busy_until
+    }
+
+    /// Picks the delay of a packet around the latency
+    fn delay(&mut self, conditions: &LinkConditions) -> Duration {
+        let Some(jitter) = &conditions.jitter else {
+            return conditions.latency.as_duration();
+        };
+
+        // A sample with a mean of 0 and a deviation of 1
+        let sample: f64 = match jitter.distribution {
+            DelayDistribution::Uniform => self.rng.gen_range(-1.0..=1.0),
+            DelayDistribution::Normal => StandardNormal.sample(&mut self.rng),
+            DelayDistribution::Pareto => {
+                let pareto = Pareto::new(1.0, PARETO_SHAPE).unwrap();
+                (pareto.sample(&mut self.rng) - PARETO_MEAN) / PARETO_STD_DEV
+            }
+        };
+
+        // Correlated like netem, by mixing in the previous sample
+        let correlation = jitter.correlation as f64 / 100.0;
+        self.last_jitter = correlation * self.last_jitter + (1.0 - correlation) * sample;
+
+        let delay = conditions.latency.as_duration().as_secs_f64()
+            + jitter.deviation.as_duration().as_secs_f64() * self.last_jitter;
+        Duration::from_secs_f64(delay.max(0.0))
+    }
+
+    /// Draws an event happening with the given percentage
+    fn chance(&mut self, percentage: f32) -> bool {
+        self.rng.gen::<f32>() * 100.0 < percentage
+    }
+}
+
+/// The links of one direction of a proxy, one per flow told apart by a
+/// mask. Every flow of a proxy has the same destination and protocol, so
+/// only the source address and port of the client tell flows apart.
+pub(crate) struct FlowLinks {
+    conditions: SharedConditions,
+    direction: Direction,
+    mask: FlowMask,
+    links: HashMap<SocketAddr, Arc<Mutex<Link>>>,
+}
+
+impl FlowLinks {
+    pub fn new(conditions: SharedConditions, direction: Direction, mask: FlowMask) -> Self {
+        Self {
+            conditions,
+            direction,
+            mask,
+            links: HashMap::new(),
+        }
+    }
+
+    /// Returns the link of the flow of a client
+    pub fn get(&mut self, client: SocketAddr) -> Arc<Mutex<Link>> {
+        let mut flow = client;
+        if !self.mask.src_address {
+            flow.set_ip(match client {
+                SocketAddr::V4(_) => [0u8; 4].into(),
+                SocketAddr::V6(_) => [0u8; 16].into(),
+            });
+        }
+        if !self.mask.src_port {
+            flow.set_port(0);
+        }
+
+        self.links
+            .entry(flow)
+            .or_insert_with(|| {
+                Arc::new(Mutex::new(Link::new(
+                    self.conditions.clone(),
+                    self.direction,
+                )))
+            })
+            .clone()
+    }
+
+    /// Forgets the flows whose links are no longer used by anyone else,
+    /// once all their connections are closed
+    pub fn prune(&mut self) {
+        self.links.retain(|_, link| Arc::strong_count(link) > 1);
+    }
+}
+
+struct Scheduled<T> {
+    deliver_at: Instant,
+    seq: u64,
+    item: T,
+}
+
+impl<T> PartialEq for Scheduled<T> {
+    fn eq(&self, other: &Self) -> bool {
+        (self.deliver_at, self.seq) == (other.deliver_at, other.seq)
+    }
+}
+
+impl<T> Eq for Scheduled<T> {}
+
+impl<T> PartialOrd for Scheduled<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Scheduled<T> {
+    // Reversed, so the binary heap pops the earliest delivery first
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.deliver_at, other.seq).cmp(&(self.deliver_at, self.seq))
+    }
+}
+
+/// Hands items to a delivery function once their delivery time is reached.
+///
+/// Delivery happens on a dedicated thread, which exits once every sender
+/// is dropped. Items still in flight at that point are discarded.
+pub(crate) struct DelayLine;
+
+impl DelayLine {
+    pub fn spawn<T, F>(
+        name: &str,
+        mut deliver: F,
+    ) -> std::io::Result<(Scheduler<T>, JoinHandle<()>)>
+    where
+        T: Send + 'static,
+        F: FnMut(T) + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        let handle = thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || Self::run(receiver, &mut deliver))?;
+        Ok((sender, handle))
+    }
+
+    fn run<T>(receiver: Receiver<(Instant, T)>, deliver: &mut impl FnMut(T)) {
+        let mut pending = BinaryHeap::new();
+        let mut seq = 0u64;
+
+        loop {
+            let now = Instant::now();
+            while pending
+                .peek()
+                .is_some_and(|next: &Scheduled<T>| next.deliver_at <= now)
+            {
+                deliver(pending.pop().unwrap().item);
+            }
+
+            let timeout = pending
+                .peek()
+                .map_or(POLL_INTERVAL, |next| next.deliver_at - now);
+
+            match receiver.recv_timeout(timeout) {
+                Ok((deliver_at, item)) => {
+                    pending.push(Scheduled {
+                        deliver_at,
+                        seq,
+                        item,
+                    });
+                    seq += 1;
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Bandwidth, Delay, GilbertElliott, Red, Reorder};
+
+    /// A link without latency and bandwidth limit, its draws seeded
+    fn link(seed: u64) -> Link {
+        let config = ApplyConfig::symmetric(0.0, Delay::ZERO, Bandwidth::UNLIMITED);
+        Link::new(Arc::new(RwLock::new(config)), Direction::Outbound).with_seed(seed)
+    }
+
+    fn conditions() -> LinkConditions {
+        LinkConditions::new(0.0, Delay::ZERO, Bandwidth::UNLIMITED).unwrap()
+    }
+
+    /// Returns which of `count` packets are lost
+    fn losses(link: &mut Link, conditions: &LinkConditions, count: usize) -> Vec<bool> {
+        (0..count).map(|_| link.is_lost(conditions)).collect()
+    }
+
+    #[test]
+    fn gilbert_elliott_loses_packets_in_bursts() {
+        let mut conditions = conditions();
+        conditions.burst_loss = Some(GilbertElliott::new(10.0, 50.0, 100.0, 0.0).unwrap());
+        let lost = losses(&mut link(1), &conditions, 100_000);
+        assert_eq!(lost, losses(&mut link(1), &conditions, 100_000));
+
+        // The link spends to_bad / (to_bad + to_good) of the packets in the
+        // bad state, and 1 / to_good packets in a row once there
+        let rate = lost.iter().filter(|lost| **lost).count() as f64 / lost.len() as f64;
+        assert!((rate - 1.0 / 6.0).abs() < 0.01, "{}", rate);
+        let bursts = lost.windows(2).filter(|w| !w[0] && w[1]).count();
+        let burst_length = lost.iter().filter(|lost| **lost).count() as f64 / bursts as f64;
+        assert!((burst_length - 2.0).abs() < 0.1, "{}", burst_length);
+    }
+
+    #[test]
+    fn gilbert_elliott_loses_with_the_probability_of_its_state() {
+        // Never leaving the good state, only its loss applies
+        let mut conditions = conditions();
+        conditions.burst_loss = Some(GilbertElliott::new(0.0, 100.0, 100.0, 0.0).unwrap());
+        assert!(!losses(&mut link(2), &conditions, 1_000).contains(&true));
+
+        // Stuck in the bad state after the first packet
+        conditions.burst_loss = Some(GilbertElliott::new(100.0, 0.0, 100.0, 0.0).unwrap());
+        assert!(!losses(&mut link(2), &conditions, 1_000).contains(&false));
+
+        conditions.burst_loss = Some(GilbertElliott::new(100.0, 0.0, 30.0, 0.0).unwrap());
+        let lost = losses(&mut link(2), &conditions, 100_000);
+        let rate = lost.iter().filter(|lost| **lost).count() as f64 / lost.len() as f64;
+        assert!((rate - 0.3).abs() < 0.01, "{}", rate);
+    }
+
+    /// Returns which of `count` packets are sent early
+    fn reorders(link: &mut Link, conditions: &LinkConditions, count: usize) -> Vec<bool> {
+        (0..count).map(|_| link.is_reordered(conditions)).collect()
+    }
+
+    #[test]
+    fn reorders_every_gapth_packet_at_most() {
+        let mut conditions = conditions();
+        conditions.reorder = Some(Reorder::new(100.0, 0.0, 5).unwrap());
+        let reordered = reorders(&mut link(3), &conditions, 20);
+        let positions: Vec<usize> = (0..20).filter(|idx| reordered[*idx]).collect();
+        assert_eq!(positions, [4, 9, 14, 19]);
+
+        conditions.reorder = Some(Reorder::new(0.0, 0.0, 1).unwrap());
+        assert!(!reorders(&mut link(3), &conditions, 1_000).contains(&true));
+    }
+
+    #[test]
+    fn reorders_candidates_with_the_probability() {
+        let mut conditions = conditions();
+        conditions.reorder = Some(Reorder::new(30.0, 0.0, 1).unwrap());
+        let reordered = reorders(&mut link(4), &conditions, 100_000);
+        assert_eq!(reordered, reorders(&mut link(4), &conditions, 100_000));
+        let rate = reordered.iter().filter(|r| **r).count() as f64 / reordered.len() as f64;
+        assert!((rate - 0.3).abs() < 0.01, "{}", rate);
+
+        // Packets sent early are still at least a gap apart
+        conditions.reorder = Some(Reorder::new(30.0, 0.0, 3).unwrap());
+        let reordered = reorders(&mut link(4), &conditions, 100_000);
+        let mut since = 0;
+        for early in reordered {
+            if early {
+                assert!(since >= 2, "{}", since);
+                since = 0;
+            } else {
+                since += 1;
+            }
+        }
+    }
+
+    #[test]
+    fn duplicates_and_corrupts_copies() {
+        let mut config = ApplyConfig::symmetric(0.0, Delay::ZERO, Bandwidth::UNLIMITED);
+        config.outbound.duplicate = 100.0;
+        config.outbound.corrupt = 100.0;
+        let mut link = Link::new(Arc::new(RwLock::new(config)), Direction::Outbound).with_seed(5);
+
+        let payload = [0u8; 16];
+        let copies = link.transmit(&payload);
+        assert_eq!(copies.len(), 2);
+        for (_, copy) in copies {
+            // A single bit is flipped
+            let flipped: u32 = copy.iter().map(|byte| byte.count_ones()).sum();
+            assert_eq!(flipped, 1);
+        }
+    }
+
+    /// Returns how many of `count` packets RED drops with a steady queue of
+    /// `length`, once its average has settled
+    fn early_drops(red: Red, length: u64, count: usize) -> usize {
+        let mut conditions = conditions();
+        conditions.red = Some(red);
+        let mut link = link(6);
+        (0..count)
+            .filter(|_| link.is_dropped_early(length, &conditions))
+            .count()
+    }
+
+    #[test]
+    fn red_drops_with_a_probability_growing_with_the_queue() {
+        let red = |gentle| Red::new(1.0, 10, 20, 0.5, gentle).unwrap();
+        assert_eq!(early_drops(red(false), 5, 10_000), 0);
+        let rate = early_drops(red(false), 15, 100_000) as f64 / 100_000.0;
+        assert!((rate - 0.25).abs() < 0.01, "{}", rate);
+        assert_eq!(early_drops(red(false), 25, 10_000), 10_000);
+
+        // Gentle RED rises to 1 at twice the maximum threshold
+        let rate = early_drops(red(true), 30, 100_000) as f64 / 100_000.0;
+        assert!((rate - 0.75).abs() < 0.01, "{}", rate);
+        assert_eq!(early_drops(red(true), 40, 10_000), 10_000);
+    }
+
+    #[test]
+    fn red_averages_the_queue_length() {
+        let mut conditions = conditions();
+        conditions.red = Some(Red::new(0.5, 10, 20, 0.5, false).unwrap());
+        let mut link = link(7);
+        for expected in [8.0, 12.0, 14.0, 15.0] {
+            link.is_dropped_early(16, &conditions);
+            assert_eq!(link.average_queue, expected);
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::process;
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::commands::RootQdisc;
+use crate::rules::PfRuleset;
+use crate::runner::{CommandRunner, Invocation};
+use crate::{ApplyConfig, FlowMask, TrafficShapingError};
+
+/// Where the session is kept between processes
+pub(super) const SESSION_PATH: &str = "/var/run/traffic_shaper.session";
+
+/// The changes made to the system by the pf or the tc backend. The session
+/// is saved after each change, so another process can undo exactly these
+/// changes, even if the process making them died halfway.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct Session {
+    /// Process that started the shaping
+    pub pid: u32,
+    pub started_at: DateTime<Local>,
+    /// Whether every change of the last `enable` was made
+    pub complete: bool,
+    /// Main ruleset before enabling, restored on cleanup if it was changed
+    pub original: Option<PfRuleset>,
+    /// Anchor holding the pf rules, once loaded
+    pub anchor: Option<String>,
+    /// Token of the reference taken on PF
+    pub token: Option<String>,
+    /// Pipes allocated to each rule
+    pub pipes: Vec<(String, RulePipes)>,
+    /// Devices shaped with tc
+    #[serde(default)]
+    pub devices: Vec<ShapedDevice>,
+}
+
+/// A device shaped with tc, and what it had before
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct ShapedDevice {
+    pub name: String,
+    /// Root qdisc replaced by the one of the shaper, none if tc listed none
+    pub original_root: Option<RootQdisc>,
+    /// Kind of the ingress qdisc it already had, which is never removed
+    #[serde(default)]
+    pub original_ingress: Option<String>,
+    /// The ifb device its ingress is redirected to, unless it is loopback
+    pub ifb: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(super) struct RulePipes {
+    pub inbound: u32,
+    pub outbound: u32,
+    /// Mask of the outbound pipe, reversed for the inbound one
+    pub mask: FlowMask,
+    /// Queues of the classes of the rule, feeding its pipes
+    pub queues: Vec<ClassQueues>,
+    /// Conditions the pipes are configured with
+    pub config: ApplyConfig,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(super) struct ClassQueues {
+    pub inbound: u32,
+    pub outbound: u32,
+    pub weight: u32,
+}
+
+impl Session {
+    /// Creates a session of the current process, which changed nothing yet
+    pub fn new() -> Self {
+        Self {
+            pid: process::id(),
+            started_at: Local::now(),
+            complete: false,
+            original: None,
+            anchor: None,
+            token: None,
+            pipes: Vec::new(),
+            devices: Vec::new(),
+        }
+    }
+
+    /// Checks if the session has no change to undo
+    pub fn is_empty(&self) -> bool {
+        self.original.is_none()
+            && self.anchor.is_none()
+            && self.token.is_none()
+            && self.pipes.is_empty()
+            && self.devices.is_empty()
+    }
+
+    /// Reads the session saved at `path`, if any. A session that cannot be
+    /// parsed, e.g. written by another version, is moved aside and reported
+    /// as an error: there is no telling what it changed, so its changes are
+    /// left for the user to undo.
+    pub fn load(path: &Path) -> Result<Option<Self>, TrafficShapingError> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        match serde_json::from_str(&contents) {
+            Ok(session) => Ok(Some(session)),
+            Err(e) => {
+                let mut aside = path.as_os_str().to_owned();
+                aside.push(".unreadable");
+                fs::rename(path, &aside)?;
+                Err(TrafficShapingError::UnreadableSession {
+                    path: Path::new(&aside).display().to_string(),
+                    reason: e.to_string(),
+                })
+            }
+        }
+    }
+
+    /// Saves the session at `path`. It is written next to it first and then
+    /// renamed into place, so a process dying while saving never leaves a
+    /// truncated session behind.
+    pub fn save(&self, path: &Path) -> Result<(), TrafficShapingError> {
+        let contents = serde_json::to_string_pretty(self).map_err(std::io::Error::from)?;
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        fs::write(&temp, contents)?;
+        fs::rename(&temp, path)?;
+        Ok(())
+    }
+
+    /// Removes the session saved at `path`, if any
+    pub fn remove(path: &Path) -> Result<(), TrafficShapingError> {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks if the process that started the session still runs
+    pub fn is_running(&self, runner: &dyn CommandRunner) -> bool {
+        runner
+            .run(&Invocation::new("ps").arg("-p").arg(self.pid.to_string()))
+            .map(|output| output.is_success())
+            .unwrap_or(false)
+    }
+}
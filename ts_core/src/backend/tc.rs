@@ -0,0 +1,674 @@
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+
+use tracing::{info, warn};
+
+use super::session::{Session, ShapedDevice, SESSION_PATH};
+use super::ShaperBackend;
+use crate::commands::{TcCommands, TC_IFB_PREFIX, TC_MAX_RULES};
+use crate::rules::RuleGenerator;
+use crate::runner::{CommandRunner, DryRunRunner, SystemRunner};
+use crate::{ApplyConfig, Direction, Interfaces, QueueSize, ShapingRule, TrafficShapingError};
+
+/// Shapes traffic with tc on Linux.
+///
+/// Egress is shaped with netem on every device with the outbound conditions,
+/// and ingress by redirecting it to an ifb device carrying the same qdiscs
+/// with the inbound conditions, like the `in` and `out` dummynet rules on
+/// macOS. Each rule gets its own `prio` band and netem qdisc, and its
+/// filters are only added on the devices it is bound to.
+///
+/// The devices are recorded in a session file before they are shaped, with
+/// the root qdisc they had, like the changes of the pf backend. Cleanup
+/// only touches these devices, and puts their original root qdisc back.
+pub struct TcBackend {
+    runner: Arc<dyn CommandRunner>,
+    tc: TcCommands,
+    /// Names of the installed rules, in band order
+    rules: Vec<String>,
+    session: Session,
+    session_path: PathBuf,
+    /// Whether the commands are printed instead of run, and the session
+    /// never saved
+    dry_run: bool,
+}
+
+impl TcBackend {
+    pub fn new() -> Self {
+        Self::with_runner(Arc::new(SystemRunner))
+    }
+
+    /// Creates a backend running tc and ip through the given runner
+    pub fn with_runner(runner: Arc<dyn CommandRunner>) -> Self {
+        Self {
+            tc: TcCommands::new(runner.clone()),
+            runner,
+            rules: Vec::new(),
+            session: Session::new(),
+            session_path: PathBuf::from(SESSION_PATH),
+            dry_run: false,
+        }
+    }
+
+    /// Creates a backend printing the tc and ip commands it would run
+    /// instead of running them. Devices and qdiscs are still listed from
+    /// the system, which needs no privileges.
+    pub fn dry_run() -> Self {
+        let runner = DryRunRunner::new()
+            .pass_through("ip -o link show")
+            .pass_through("tc qdisc show");
+        Self {
+            dry_run: true,
+            ..Self::with_runner(Arc::new(runner))
+        }
+    }
+
+    /// Keeps the session in another file than
+    /// /var/run/traffic_shaper.session
+    pub fn with_session_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.session_path = path.into();
+        self
+    }
+}
+
+impl Default for TcBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TcBackend {
+    fn rule_idx(&self, rule: &str) -> Result<usize, TrafficShapingError> {
+        self.rules
+            .iter()
+            .position(|name| name == rule)
+            .ok_or_else(|| TrafficShapingError::UnknownRule(rule.to_string()))
+    }
+
+    /// Configures the netem qdisc of a rule with the conditions of the
+    /// direction the device carries
+    fn configure(
+        &self,
+        dev: &str,
+        rule_idx: usize,
+        config: &ApplyConfig,
+    ) -> Result<(), TrafficShapingError> {
+        // The ifb devices carry the ingress of the real ones
+        let direction = if dev.starts_with(TC_IFB_PREFIX) {
+            Direction::Inbound
+        } else {
+            Direction::Outbound
+        };
+        self.tc
+            .configure_netem(dev, rule_idx, config.conditions(direction))
+    }
+
+    fn install(
+        &mut self,
+        devices: &[String],
+        rules: &[ShapingRule],
+        filters: &[Vec<String>],
+    ) -> Result<(), TrafficShapingError> {
+        for (idx, dev) in devices.iter().enumerate() {
+            // Loopback traffic would be delayed twice if its ingress was
+            // shaped as well, so it is only shaped on egress
+            let ifb = (!Interfaces::is_loopback(dev)).then(|| format!("{}{}", TC_IFB_PREFIX, idx));
+
+            // Saved before changing the device, so its original root qdisc
+            // is restored even if the process dies halfway
+            let original_root = self.tc.root_qdisc(dev)?;
+            let original_ingress = match ifb {
+                Some(_) => self.tc.ingress_qdisc(dev)?,
+                None => None,
+            };
+            self.session.devices.push(ShapedDevice {
+                name: dev.clone(),
+                original_root,
+                original_ingress: original_ingress.clone(),
+                ifb: ifb.clone(),
+            });
+            self.save_session()?;
+
+            // Replacing the ingress qdisc of another tool would drop its
+            // filters, which cannot be put back on cleanup
+            if let Some(kind) = original_ingress {
+                return Err(TrafficShapingError::Unsupported(format!(
+                    "shaping the ingress of {}, which already has a qdisc ({})",
+                    dev, kind
+                )));
+            }
+
+            let mut targets = vec![dev.clone()];
+            if let Some(ifb) = ifb {
+                self.tc.redirect_ingress(dev, &ifb)?;
+                targets.push(ifb);
+            }
+
+            for target in &targets {
+                self.tc.install_root(target, rules.len())?;
+                self.configure_rules(target, rules)?;
+                for (rule_idx, rule) in rules.iter().enumerate() {
+                    if !rule.config.interfaces.includes(dev) {
+                        continue;
+                    }
+                    for filter in &filters[rule_idx] {
+                        self.tc.add_filter(target, rule_idx, filter)?;
+                    }
+                }
+            }
+            info!("configured tc on {}", targets.join(" and "));
+        }
+        Ok(())
+    }
+
+    /// Returns the devices of the session and their ifb devices, which
+    /// carry the qdiscs of the rules
+    fn targets(&self) -> Vec<String> {
+        self.session
+            .devices
+            .iter()
+            .flat_map(|device| std::iter::once(device.name.clone()).chain(device.ifb.clone()))
+            .collect()
+    }
+
+    fn save_session(&self) -> Result<(), TrafficShapingError> {
+        if self.dry_run {
+            return Ok(());
+        }
+        self.session.save(&self.session_path)
+    }
+
+    /// Checks if a saved session was left behind: by a process that died
+    /// before completing it, or with none of its devices shaped anymore,
+    /// e.g. after a reboot
+    fn is_stale(&self, session: &Session) -> Result<bool, TrafficShapingError> {
+        if !session.complete {
+            return Ok(session.pid == process::id() || !session.is_running(&*self.runner));
+        }
+
+        for device in &session.devices {
+            if self.tc.is_shaped(&device.name)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Undoes what is left of a stale session before starting a new one.
+    /// Fails if the saved session is still active.
+    fn recover_stale_session(&mut self) -> Result<(), TrafficShapingError> {
+        // A dry run has not shaped the devices it would undo
+        if self.dry_run {
+            return Ok(());
+        }
+        let Some(saved) = Session::load(&self.session_path)? else {
+            return Ok(());
+        };
+        if !self.is_stale(&saved)? {
+            return Err(TrafficShapingError::SessionActive(saved.pid));
+        }
+
+        warn!(
+            "recovering the stale session started by process {} at {}",
+            saved.pid, saved.started_at
+        );
+        self.session = saved;
+        if let Err(e) = self.undo() {
+            warn!("failed to undo the stale session: {}", e);
+        }
+        self.session = Session::new();
+        Ok(())
+    }
+
+    /// Puts the devices of the session back the way they were, in the
+    /// reverse order they were shaped. Parts may be missing after a failed
+    /// `install`, and a root qdisc replaced by someone else since is left
+    /// alone.
+    fn undo(&mut self) -> Result<(), TrafficShapingError> {
+        while let Some(device) = self.session.devices.last().cloned() {
+            // Removing the ingress qdisc first stops redirecting into the ifb.
+            // One the device had before is not the shaper's.
+            if device.ifb.is_some() && device.original_ingress.is_none() {
+                self.tc.remove_ingress(&device.name)?;
+            }
+            if self.dry_run || self.tc.is_shaped(&device.name)? {
+                match &device.original_root {
+                    Some(root) => self.tc.restore_root(&device.name, root)?,
+                    None => self.tc.reset_device(&device.name)?,
+                }
+            }
+            if let Some(ifb) = &device.ifb {
+                if self.dry_run || self.tc.list_devices()?.contains(ifb) {
+                    self.tc.delete_device(ifb)?;
+                }
+            }
+            info!("restored the qdiscs of {}", device.name);
+
+            self.session.devices.pop();
+            self.save_session()?;
+        }
+
+        if !self.dry_run {
+            Session::remove(&self.session_path)?;
+        }
+        Ok(())
+    }
+
+    /// Removes the qdiscs of the shaper without a session telling which
+    /// devices it shaped, or what their root qdisc was: the devices carrying
+    /// them are left with the default root qdisc of the kernel
+    fn remove_leftovers(&self) -> Result<(), TrafficShapingError> {
+        let devices = self.tc.list_devices()?;
+        for dev in devices.iter().filter(|dev| !dev.starts_with(TC_IFB_PREFIX)) {
+            if self.tc.is_shaped(dev)? {
+                warn!("resetting {} to the default root qdisc", dev);
+                self.tc.reset_device(dev)?;
+            }
+        }
+        for dev in devices.iter().filter(|dev| dev.starts_with(TC_IFB_PREFIX)) {
+            self.tc.delete_device(dev)?;
+        }
+        Ok(())
+    }
+
+    fn configure_rules(&self, dev: &str, rules: &[ShapingRule]) -> Result<(), TrafficShapingError> {
+        for (rule_idx, rule) in rules.iter().enumerate() {
+            self.configure(dev, rule_idx, &ApplyConfig::from(&rule.config))?;
+        }
+        Ok(())
+    }
+}
+
+impl ShaperBackend for TcBackend {
+    fn enable(&mut self, rules: &[ShapingRule]) -> Result<(), TrafficShapingError> {
+        if rules.len() > TC_MAX_RULES {
+            return Err(TrafficShapingError::Unsupported(format!(
+                "more than {} rules",
+                TC_MAX_RULES
+            )));
+        }
+
+        for rule in rules {
+            if !rule.config.flow_mask.is_empty() {
+                return Err(TrafficShapingError::Unsupported(
+                    "flow masks on netem".to_string(),
+                ));
+            }
+            if !rule.config.classes.is_empty() {
+                return Err(TrafficShapingError::Unsupported(
+                    "traffic classes on netem".to_string(),
+                ));
+            }
+            check_supported(&ApplyConfig::from(&rule.config))?;
+        }
+
+        // Only update the conditions if the same rules are already installed
+        let names: Vec<String> = rules.iter().map(|rule| rule.name.clone()).collect();
+        if names == self.rules && !self.session.is_empty() {
+            for target in self.targets() {
+                self.configure_rules(&target, rules)?;
+            }
+            return Ok(());
+        }
+
+        // Bands and filters of other rules cannot be updated in place
+        if self.session.is_empty() {
+            self.recover_stale_session()?;
+        } else {
+            self.undo()?;
+        }
+        self.rules.clear();
+        self.session = Session::new();
+        self.save_session()?;
+
+        let mut filters = Vec::new();
+        for rule in rules {
+            filters.push(RuleGenerator::generate_tc_filters(&rule.config)?);
+        }
+
+        let available = self.tc.list_devices()?;
+        for rule in rules {
+            if let Interfaces::Named(names) = &rule.config.interfaces {
+                if let Some(missing) = names.iter().find(|name| !available.contains(name)) {
+                    return Err(TrafficShapingError::UnknownInterface(missing.clone()));
+                }
+            }
+        }
+
+        // Only the devices some rule is bound to are touched
+        let devices: Vec<String> = available
+            .into_iter()
+            .filter(|dev| !dev.starts_with(TC_IFB_PREFIX))
+            .filter(|dev| {
+                rules
+                    .iter()
+                    .any(|rule| rule.config.interfaces.includes(dev))
+            })
+            .collect();
+        if let Err(e) = self.install(&devices, rules, &filters) {
+            // A device left redirecting its ingress to a missing ifb would
+            // drop all the traffic it receives
+            if let Err(undo_error) = self.undo() {
+                warn!("failed to undo the partial install: {}", undo_error);
+            }
+            self.session = Session::new();
+            return Err(e);
+        }
+
+        self.rules = names;
+        self.session.complete = true;
+        self.save_session()
+    }
+
+    fn apply(&mut self, rule: &str, config: &ApplyConfig) -> Result<(), TrafficShapingError> {
+        let rule_idx = self.rule_idx(rule)?;
+        check_supported(config)?;
+        for target in self.targets() {
+            self.configure(&target, rule_idx, config)?;
+        }
+        Ok(())
+    }
+
+    fn requires_privileges(&self) -> bool {
+        !self.dry_run
+    }
+
+    /// Checks that a device of the session still carries the qdiscs of the
+    /// shaper
+    fn is_active(&self) -> Result<bool, TrafficShapingError> {
+        for device in &self.session.devices {
+            if self.tc.is_shaped(&device.name)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn cleanup(&mut self) -> Result<(), TrafficShapingError> {
+        // In another process than the one that enabled shaping, undo the
+        // changes of the saved session
+        if self.session.is_empty() {
+            match Session::load(&self.session_path)? {
+                Some(saved) => self.session = saved,
+                None => {
+                    warn!(
+                        "no session in {}, only removing the qdiscs of the shaper",
+                        self.session_path.display()
+                    );
+                    self.rules.clear();
+                    return self.remove_leftovers();
+                }
+            }
+        }
+
+        self.undo()?;
+        self.session = Session::new();
+        self.rules.clear();
+        Ok(())
+    }
+}
+
+/// Fails on the queue settings netem has no equivalent for, as it only
+/// limits its queue in packets and drops from its tail
+fn check_supported(config: &ApplyConfig) -> Result<(), TrafficShapingError> {
+    for conditions in [&config.inbound, &config.outbound] {
+        let unsupported = [
+            (
+                "queue size in bytes",
+                matches!(conditions.queue, Some(QueueSize::Bytes(_))),
+            ),
+            ("RED", conditions.red.is_some()),
+        ];
+
+        if let Some((name, _)) = unsupported.iter().find(|(_, set)| *set) {
+            return Err(TrafficShapingError::Unsupported(format!(
+                "{} on netem",
+                name
+            )));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{CommandOutput, FakeRunner};
+    use crate::{Bandwidth, Delay, LinkConditions, Output, PortRange, Protocol, TrafficConfig};
+
+    fn rule(name: &str) -> ShapingRule {
+        ShapingRule {
+            name: name.to_string(),
+            config: TrafficConfig::new(
+                1.0,
+                Delay::from_millis(50),
+                Bandwidth::from_bps(1_000_000),
+                Protocol::Udp,
+                None,
+                Some(PortRange::single(5000)),
+                Output::None,
+            )
+            .unwrap(),
+        }
+    }
+
+    /// A backend shaping eth0 and lo through `fake`, its session kept in a
+    /// file of the test
+    fn backend(fake: &Arc<FakeRunner>, test: &str) -> (TcBackend, PathBuf) {
+        let path = std::env::temp_dir().join(format!(
+            "traffic_shaper-tc-{}-{}.session",
+            test,
+            process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        fake.respond(
+            "ip -o link show",
+            CommandOutput::success(
+                "1: lo: <LOOPBACK,UP,LOWER_UP> mtu 65536\n\
+                 2: eth0@if5: <BROADCAST,MULTICAST,UP,LOWER_UP> mtu 1500\n",
+            ),
+        );
+        fake.respond(
+            "ip -o link show",
+            CommandOutput::success(
+                "1: lo: <LOOPBACK,UP,LOWER_UP> mtu 65536\n\
+                 2: eth0@if5: <BROADCAST,MULTICAST,UP,LOWER_UP> mtu 1500\n\
+                 3: tsifb0: <BROADCAST,NOARP,UP,LOWER_UP> mtu 1500\n",
+            ),
+        );
+        // The original root qdiscs, then the ones of the shaper
+        let shaped =
+            "qdisc prio 1: root refcnt 2 bands 4 priomap 1 2 2 2 1 2 0 0 1 1 1 1 1 1 1 1\n\
+                      qdisc netem 10: parent 1:4 limit 1000 delay 50ms loss 1% rate 1Mbit\n";
+        fake.respond(
+            "tc qdisc show dev eth0",
+            CommandOutput::success("qdisc fq 8001: root refcnt 2 limit 10000p flow_limit 100p\n"),
+        );
+        fake.respond("tc qdisc show dev eth0", CommandOutput::success(shaped));
+        fake.respond(
+            "tc qdisc show dev lo",
+            CommandOutput::success("qdisc noqueue 0: root refcnt 2\n"),
+        );
+        fake.respond("tc qdisc show dev lo", CommandOutput::success(shaped));
+
+        let backend = TcBackend::with_runner(fake.clone()).with_session_path(&path);
+        (backend, path)
+    }
+
+    fn enabled_lines() -> Vec<String> {
+        let prio = "root handle 1: prio bands 4 priomap 1 2 2 2 1 2 0 0 1 1 1 1 1 1 1 1";
+        let netem = "parent 1:4 handle 10: netem delay 50ms loss 1% rate 1000000bit";
+        let ipv4 = "parent 1: protocol ip prio 1 u32 match ip protocol 17 0xff \
+                    match ip dport 5000 0xffff flowid 1:4";
+        let ipv6 = "parent 1: protocol ipv6 prio 2 u32 match ip6 protocol 17 0xff \
+                    match ip6 dport 5000 0xffff flowid 1:4";
+        let mut lines = vec![
+            "ip -o link show".to_string(),
+            "tc qdisc show dev eth0".to_string(),
+            "tc qdisc show dev eth0".to_string(),
+            "ip link add name tsifb0 type ifb".to_string(),
+            "ip link set dev tsifb0 up".to_string(),
+            "tc qdisc replace dev eth0 handle ffff: ingress".to_string(),
+            "tc filter add dev eth0 parent ffff: protocol all u32 match u32 0 0 \
+             action mirred egress redirect dev tsifb0"
+                .to_string(),
+        ];
+        for dev in ["eth0", "tsifb0", "lo"] {
+            if dev == "lo" {
+                lines.push("tc qdisc show dev lo".to_string());
+            }
+            lines.push(format!("tc qdisc replace dev {} {}", dev, prio));
+            lines.push(format!("tc qdisc replace dev {} {}", dev, netem));
+            lines.push(format!("tc filter add dev {} {}", dev, ipv4));
+            lines.push(format!("tc filter add dev {} {}", dev, ipv6));
+        }
+        lines
+    }
+
+    /// Devices are restored in the reverse order they were shaped: lo had
+    /// the default root qdisc, eth0 gets its fq qdisc back
+    fn cleanup_lines() -> Vec<String> {
+        [
+            "tc qdisc show dev lo",
+            "tc qdisc del dev lo root",
+            "tc qdisc del dev eth0 ingress",
+            "tc qdisc show dev eth0",
+            "tc qdisc replace dev eth0 root handle 8001: fq limit 10000p flow_limit 100p",
+            "ip -o link show",
+            "ip link del dev tsifb0",
+        ]
+        .map(String::from)
+        .to_vec()
+    }
+
+    #[test]
+    fn enable_apply_cleanup() {
+        let fake = Arc::new(FakeRunner::new());
+        let (mut backend, path) = backend(&fake, "enable_apply_cleanup");
+
+        backend.enable(&[rule("default")]).unwrap();
+        assert_eq!(fake.command_lines(), enabled_lines());
+
+        let saved = Session::load(&path).unwrap().unwrap();
+        assert!(saved.complete);
+        let devices: Vec<(&str, Option<&str>, Option<&str>)> = saved
+            .devices
+            .iter()
+            .map(|device| {
+                (
+                    device.name.as_str(),
+                    device.original_root.as_ref().map(|root| root.kind.as_str()),
+                    device.ifb.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            devices,
+            [
+                ("eth0", Some("fq"), Some("tsifb0")),
+                ("lo", Some("noqueue"), None)
+            ]
+        );
+
+        // The ifb device carries the inbound conditions
+        fake.clear();
+        let config = ApplyConfig {
+            inbound: LinkConditions::new(0.0, Delay::from_millis(10), Bandwidth::UNLIMITED)
+                .unwrap(),
+            outbound: LinkConditions::new(5.0, Delay::from_millis(80), Bandwidth::UNLIMITED)
+                .unwrap(),
+        };
+        backend.apply("default", &config).unwrap();
+        assert_eq!(
+            fake.command_lines(),
+            [
+                "tc qdisc replace dev eth0 parent 1:4 handle 10: netem delay 80ms loss 5%",
+                "tc qdisc replace dev tsifb0 parent 1:4 handle 10: netem delay 10ms loss 0%",
+                "tc qdisc replace dev lo parent 1:4 handle 10: netem delay 80ms loss 5%",
+            ]
+        );
+        assert!(matches!(
+            backend.apply("other", &config),
+            Err(TrafficShapingError::UnknownRule(_))
+        ));
+
+        fake.clear();
+        backend.cleanup().unwrap();
+        assert_eq!(fake.command_lines(), cleanup_lines());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn cleanup_undoes_the_saved_session() {
+        let fake = Arc::new(FakeRunner::new());
+        let (mut backend, path) = backend(&fake, "cleanup_undoes_the_saved_session");
+        backend.enable(&[rule("default")]).unwrap();
+
+        // Another process only knows the devices from the session
+        fake.clear();
+        let mut other = TcBackend::with_runner(fake.clone()).with_session_path(&path);
+        other.cleanup().unwrap();
+        assert_eq!(fake.command_lines(), cleanup_lines());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn refuses_to_replace_an_existing_ingress_qdisc() {
+        let fake = Arc::new(FakeRunner::new());
+        let path = std::env::temp_dir().join(format!(
+            "traffic_shaper-tc-refuses_to_replace_an_existing_ingress_qdisc-{}.session",
+            process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        fake.respond(
+            "ip -o link show",
+            CommandOutput::success("2: eth0@if5: <BROADCAST,MULTICAST,UP,LOWER_UP> mtu 1500\n"),
+        );
+        fake.respond(
+            "tc qdisc show dev eth0",
+            CommandOutput::success(
+                "qdisc fq 8001: root refcnt 2 limit 10000p flow_limit 100p\n\
+                 qdisc clsact ffff: parent ffff:fff1\n",
+            ),
+        );
+        let mut backend = TcBackend::with_runner(fake.clone()).with_session_path(&path);
+
+        assert!(matches!(
+            backend.enable(&[rule("default")]),
+            Err(TrafficShapingError::Unsupported(_))
+        ));
+        // The qdiscs of the device are listed, and left alone
+        assert_eq!(
+            fake.command_lines(),
+            [
+                "ip -o link show",
+                "tc qdisc show dev eth0",
+                "tc qdisc show dev eth0",
+                "tc qdisc show dev eth0",
+                "ip -o link show",
+            ]
+        );
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn failed_enable_restores_the_devices() {
+        let fake = Arc::new(FakeRunner::new());
+        let (mut backend, path) = backend(&fake, "failed_enable_restores_the_devices");
+        fake.respond(
+            "tc qdisc replace dev lo root",
+            CommandOutput::failure(2, "RTNETLINK answers: Operation not permitted"),
+        );
+
+        assert!(backend.enable(&[rule("default")]).is_err());
+        let lines = fake.command_lines();
+        let failed = lines
+            .iter()
+            .position(|line| line.starts_with("tc qdisc replace dev lo root"))
+            .unwrap();
+        assert_eq!(lines[failed + 1..], cleanup_lines());
+        assert!(!path.exists());
+    }
+}
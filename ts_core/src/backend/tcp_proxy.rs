@@ -0,0 +1,535 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use tracing::{error, info};
+
+use super::proxy::{single_rule, FlowLinks, Link, SharedConditions, POLL_INTERVAL};
+use super::ShaperBackend;
+use crate::{ApplyConfig, Direction, LinkConditions, ShapingRule, TrafficShapingError};
+
+/// Bytes read at once, about one segment on an Ethernet path
+const CHUNK_SIZE: usize = 1460;
+/// Bytes buffered per direction while they wait out the delay, before
+/// reading pauses so a slow link pushes back on the sender. The buffer
+/// holds twice the bandwidth-delay product of the link, within these bounds.
+const MIN_BYTES_IN_FLIGHT: usize = 64 * CHUNK_SIZE;
+const MAX_BYTES_IN_FLIGHT: usize = 64 * 1024 * 1024;
+/// How long connecting to upstream may take before the client is dropped
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Relays TCP connections from a local port to an upstream address in
+/// userspace. Every chunk of the stream is delayed and paced by the link
+/// conditions, and a lost chunk stalls the stream for a retransmission
+/// timeout instead of being dropped. Needs no privileges, but clients have
+/// to connect to the listening address instead of the upstream one.
+///
+/// Data sent by clients gets the outbound conditions and data sent back by
+/// upstream the inbound ones. Conditions passed to `apply` take effect on
+/// open connections as well. Reordering, duplication and corruption are
+/// unsupported, as they apply to segments the proxy never sees.
+/// Only a single rule is supported, and the interface, address, port
+/// and protocol filters of its config are ignored. Connections share the
+/// link unless the flow mask of the rule includes their source address or
+/// port, as set when the proxy starts.
+pub struct TcpProxyBackend {
+    listen: SocketAddr,
+    upstream: SocketAddr,
+    rule: String,
+    conditions: SharedConditions,
+    server: Option<Server>,
+}
+
+struct Server {
+    local_addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+impl TcpProxyBackend {
+    pub fn new(listen: SocketAddr, upstream: SocketAddr) -> Self {
+        Self {
+            listen,
+            upstream,
+            rule: String::new(),
+            conditions: Arc::new(RwLock::new(ApplyConfig::default())),
+            server: None,
+        }
+    }
+
+    /// Returns the address the proxy listens on once enabled, which tells
+    /// the port picked by the system when listening on port 0
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server.as_ref().map(|server| server.local_addr)
+    }
+}
+
+impl ShaperBackend for TcpProxyBackend {
+    fn enable(&mut self, rules: &[ShapingRule]) -> Result<(), TrafficShapingError> {
+        let rule = single_rule(rules)?;
+        let config = ApplyConfig::from(&rule.config);
+        check_supported(&config)?;
+        self.rule = rule.name.clone();
+        *self.conditions.write().unwrap() = config;
+
+        // Only update the conditions if the proxy is already running
+        if self.server.is_some() {
+            return Ok(());
+        }
+
+        let listener = TcpListener::bind(self.listen)?;
+        listener.set_nonblocking(true)?;
+        let local_addr = listener.local_addr()?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut acceptor = Acceptor {
+            upstream: self.upstream,
+            stop: stop.clone(),
+            // Connections share the link, as they would share a dummynet pipe,
+            // unless the flow mask tells them apart
+            upstream_links: FlowLinks::new(
+                self.conditions.clone(),
+                Direction::Outbound,
+                rule.config.flow_mask,
+            ),
+            downstream_links: FlowLinks::new(
+                self.conditions.clone(),
+                Direction::Inbound,
+                rule.config.flow_mask,
+            ),
+            handles: Vec::new(),
+        };
+        let handle = thread::Builder::new()
+            .name("tcp-proxy-acceptor".to_string())
+            .spawn(move || acceptor.run(listener))?;
+
+        info!("tcp proxy relaying {} to {}", local_addr, self.upstream);
+        self.server = Some(Server {
+            local_addr,
+            stop,
+            handle,
+        });
+
+        Ok(())
+    }
+
+    fn apply(&mut self, rule: &str, config: &ApplyConfig) -> Result<(), TrafficShapingError> {
+        if rule != self.rule {
+            return Err(TrafficShapingError::UnknownRule(rule.to_string()));
+        }
+        check_supported(config)?;
+        *self.conditions.write().unwrap() = config.clone();
+        Ok(())
+    }
+
+    fn is_active(&self) -> Result<bool, TrafficShapingError> {
+        Ok(self.server.is_some())
+    }
+
+    fn requires_privileges(&self) -> bool {
+        false
+    }
+
+    fn cleanup(&mut self) -> Result<(), TrafficShapingError> {
+        if let Some(server) = self.server.take() {
+            server.stop.store(true, Ordering::Relaxed);
+            let _ = server.handle.join();
+            info!("tcp proxy stopped");
+        }
+        Ok(())
+    }
+}
+
+/// Fails on the impairments and queue management of single packets, which
+/// the proxy cannot reproduce as it relays the stream and not its segments
+fn check_supported(config: &ApplyConfig) -> Result<(), TrafficShapingError> {
+    for conditions in [&config.inbound, &config.outbound] {
+        let unsupported = [
+            ("reordering", conditions.reorder.is_some()),
+            ("duplication", conditions.duplicate > 0.0),
+            ("corruption", conditions.corrupt > 0.0),
+            ("queue size", conditions.queue.is_some()),
+            ("RED", conditions.red.is_some()),
+            ("ECN", conditions.ecn),
+        ];
+
+        if let Some((impairment, _)) = unsupported.iter().find(|(_, set)| *set) {
+            return Err(TrafficShapingError::Unsupported(format!(
+                "{} on the TCP proxy",
+                impairment
+            )));
+        }
+    }
+    Ok(())
+}
+
+struct Acceptor {
+    upstream: SocketAddr,
+    stop: Arc<AtomicBool>,
+    upstream_links: FlowLinks,
+    downstream_links: FlowLinks,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl Acceptor {
+    fn run(&mut self, listener: TcpListener) {
+        while !self.stop.load(Ordering::Relaxed) {
+            match listener.accept() {
+                Ok((client, addr)) => {
+                    if let Err(e) = self.relay(client, addr) {
+                        error!("tcp proxy failed to relay connection from {}: {}", addr, e);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => thread::sleep(POLL_INTERVAL),
+                Err(e) => {
+                    error!("tcp proxy failed to accept: {}", e);
+                    break;
+                }
+            }
+            self.handles.retain(|handle| !handle.is_finished());
+            self.upstream_links.prune();
+            self.downstream_links.prune();
+        }
+
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+
+    /// Relays a connection from its own thread, so an upstream slow to
+    /// answer holds back no other client
+    fn relay(&mut self, client: TcpStream, addr: SocketAddr) -> std::io::Result<()> {
+        client.set_nonblocking(false)?;
+
+        let upstream = self.upstream;
+        let upstream_link = self.upstream_links.get(addr);
+        let downstream_link = self.downstream_links.get(addr);
+        let stop = self.stop.clone();
+        self.handles.push(
+            thread::Builder::new()
+                .name(format!("tcp-proxy-connection-{}", addr))
+                .spawn(move || {
+                    let connection = Connection {
+                        client,
+                        upstream_link,
+                        downstream_link,
+                        stop,
+                    };
+                    if let Err(e) = connection.run(upstream) {
+                        error!("tcp proxy failed to relay connection from {}: {}", addr, e);
+                    }
+                })?,
+        );
+        Ok(())
+    }
+}
+
+/// A client connection, and the links of both its directions
+struct Connection {
+    client: TcpStream,
+    upstream_link: Arc<Mutex<Link>>,
+    downstream_link: Arc<Mutex<Link>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl Connection {
+    /// Connects to upstream and copies both directions until they end
+    fn run(self, upstream: SocketAddr) -> std::io::Result<()> {
+        let upstream = TcpStream::connect_timeout(&upstream, CONNECT_TIMEOUT)?;
+        for stream in [&self.client, &upstream] {
+            stream.set_nodelay(true)?;
+            stream.set_read_timeout(Some(POLL_INTERVAL))?;
+        }
+
+        let sending = self.pump(
+            self.client.try_clone()?,
+            upstream.try_clone()?,
+            self.upstream_link.clone(),
+        )?;
+        let receiving = self.pump(
+            upstream,
+            self.client.try_clone()?,
+            self.downstream_link.clone(),
+        )?;
+        for handle in sending.into_iter().chain(receiving) {
+            let _ = handle.join();
+        }
+        Ok(())
+    }
+
+    /// Copies `from` into `to` through the link, with a reader thread
+    /// scheduling chunks and a writer thread delivering them in order
+    fn pump(
+        &self,
+        from: TcpStream,
+        to: TcpStream,
+        link: Arc<Mutex<Link>>,
+    ) -> std::io::Result<[JoinHandle<()>; 2]> {
+        let (sender, receiver) = mpsc::channel();
+        let in_flight = Arc::new(InFlight::default());
+
+        let stop = self.stop.clone();
+        let reader_in_flight = in_flight.clone();
+        let reader = thread::Builder::new()
+            .name("tcp-proxy-reader".to_string())
+            .spawn(move || read_chunks(from, link, sender, reader_in_flight, stop))?;
+
+        let stop = self.stop.clone();
+        let writer = thread::Builder::new()
+            .name("tcp-proxy-writer".to_string())
+            .spawn(move || write_chunks(to, receiver, in_flight, stop))?;
+
+        Ok([reader, writer])
+    }
+}
+
+/// A chunk of the stream and when it reaches the other end. An empty chunk
+/// stands for the end of the stream.
+type Chunk = (Instant, Vec<u8>);
+
+/// The bytes of one direction read but not written yet
+#[derive(Default)]
+struct InFlight {
+    bytes: Mutex<usize>,
+    written: Condvar,
+}
+
+impl InFlight {
+    /// Waits until `len` more bytes fit under the limit of the link, then
+    /// counts them. Returns false if asked to stop first.
+    fn reserve(&self, len: usize, limit: usize, stop: &AtomicBool) -> bool {
+        let mut bytes = self.bytes.lock().unwrap();
+        // A chunk always fits an empty buffer, whatever the limit
+        while *bytes > 0 && *bytes + len > limit {
+            if stop.load(Ordering::Relaxed) {
+                return false;
+            }
+            bytes = self.written.wait_timeout(bytes, POLL_INTERVAL).unwrap().0;
+        }
+        *bytes += len;
+        true
+    }
+
+    fn release(&self, len: usize) {
+        *self.bytes.lock().unwrap() -= len;
+        self.written.notify_one();
+    }
+}
+
+/// Returns how many bytes a direction may buffer: twice its bandwidth-delay
+/// product, so the pacing of the link limits the rate and not the buffer
+fn in_flight_limit(conditions: &LinkConditions) -> usize {
+    // A bandwidth of 0 means unlimited
+    let bps = conditions.max_bandwidth.bps();
+    if bps == 0 {
+        return MAX_BYTES_IN_FLIGHT;
+    }
+
+    let mut delay = conditions.latency.as_duration();
+    if let Some(jitter) = &conditions.jitter {
+        delay += jitter.deviation.as_duration();
+    }
+    let bdp = bps as f64 / 8.0 * delay.as_secs_f64();
+    ((2.0 * bdp) as usize).clamp(MIN_BYTES_IN_FLIGHT, MAX_BYTES_IN_FLIGHT)
+}
+
+fn read_chunks(
+    mut from: TcpStream,
+    link: Arc<Mutex<Link>>,
+    sender: Sender<Chunk>,
+    in_flight: Arc<InFlight>,
+    stop: Arc<AtomicBool>,
+) {
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    while !stop.load(Ordering::Relaxed) {
+        let len = match from.read(&mut buf) {
+            Ok(len) => len,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+            Err(_) => 0,
+        };
+
+        // The limit follows the conditions set by `apply`
+        let limit = in_flight_limit(&link.lock().unwrap().conditions());
+        if !in_flight.reserve(len, limit, &stop) {
+            break;
+        }
+
+        // The end of the stream is delayed like data, so it cannot overtake it
+        let deliver_at = link.lock().unwrap().transmit_reliably(len);
+        if sender.send((deliver_at, buf[..len].to_vec())).is_err() || len == 0 {
+            break;
+        }
+    }
+}
+
+fn write_chunks(
+    mut to: TcpStream,
+    receiver: Receiver<Chunk>,
+    in_flight: Arc<InFlight>,
+    stop: Arc<AtomicBool>,
+) {
+    while !stop.load(Ordering::Relaxed) {
+        let (deliver_at, chunk) = match receiver.recv_timeout(POLL_INTERVAL) {
+            Ok(chunk) => chunk,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        // Chunks are written in the order they were read, even if a later
+        // chunk is due earlier because the latency dropped in between
+        while !stop.load(Ordering::Relaxed) {
+            let now = Instant::now();
+            if now >= deliver_at {
+                break;
+            }
+            thread::sleep((deliver_at - now).min(POLL_INTERVAL));
+        }
+
+        if chunk.is_empty() || to.write_all(&chunk).is_err() {
+            break;
+        }
+        in_flight.release(chunk.len());
+    }
+
+    let _ = to.shutdown(Shutdown::Write);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Bandwidth, Delay, Output, Protocol, TrafficConfig};
+
+    /// Echoes every connection back to itself
+    fn echo_upstream() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { break };
+                thread::spawn(move || {
+                    let mut reader = stream.try_clone().unwrap();
+                    let _ = std::io::copy(&mut reader, &mut stream);
+                });
+            }
+        });
+        addr
+    }
+
+    /// Answers every connection with the number of bytes it sent
+    fn counting_upstream() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { break };
+                thread::spawn(move || {
+                    let count = std::io::copy(&mut stream, &mut std::io::sink()).unwrap();
+                    let _ = stream.write_all(&count.to_be_bytes());
+                });
+            }
+        });
+        addr
+    }
+
+    fn config(latency: Delay) -> TrafficConfig {
+        TrafficConfig::new(
+            0.0,
+            latency,
+            Bandwidth::UNLIMITED,
+            Protocol::Tcp,
+            None,
+            None,
+            Output::None,
+        )
+        .unwrap()
+    }
+
+    fn enabled_backend(upstream: SocketAddr, latency: Delay) -> TcpProxyBackend {
+        let mut backend = TcpProxyBackend::new("127.0.0.1:0".parse().unwrap(), upstream);
+        backend
+            .enable(&[ShapingRule {
+                name: "default".to_string(),
+                config: config(latency),
+            }])
+            .unwrap();
+        backend
+    }
+
+    fn connect(backend: &TcpProxyBackend) -> TcpStream {
+        let client = TcpStream::connect(backend.local_addr().unwrap()).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        client
+    }
+
+    fn round_trip(client: &mut TcpStream, payload: &[u8]) -> Duration {
+        let started = Instant::now();
+        client.write_all(payload).unwrap();
+        let mut buf = vec![0u8; payload.len()];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, payload);
+        started.elapsed()
+    }
+
+    #[test]
+    fn applies_new_conditions_to_open_connections() {
+        let mut backend = enabled_backend(echo_upstream(), Delay::from_millis(100));
+        let mut client = connect(&backend);
+
+        // The latency is added to both directions
+        let before = round_trip(&mut client, b"before");
+        assert!(before >= Duration::from_millis(200), "{:?}", before);
+
+        backend
+            .apply(
+                "default",
+                &ApplyConfig::from(&config(Delay::from_millis(10))),
+            )
+            .unwrap();
+        let after = round_trip(&mut client, b"after");
+        assert!(after >= Duration::from_millis(20), "{:?}", after);
+        assert!(after < Duration::from_millis(150), "{:?}", after);
+
+        backend.cleanup().unwrap();
+    }
+
+    #[test]
+    fn latency_does_not_limit_throughput() {
+        let mut backend = enabled_backend(counting_upstream(), Delay::from_millis(200));
+        let mut client = connect(&backend);
+
+        // Buffering 64 segments would take over 20 s to relay this at 200 ms
+        let payload = vec![0u8; 8 * 1024 * 1024];
+        let started = Instant::now();
+        client.write_all(&payload).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut count = [0u8; 8];
+        client.read_exact(&mut count).unwrap();
+
+        assert_eq!(u64::from_be_bytes(count), payload.len() as u64);
+        assert!(
+            started.elapsed() < Duration::from_secs(5),
+            "{:?}",
+            started.elapsed()
+        );
+
+        backend.cleanup().unwrap();
+    }
+
+    #[test]
+    fn limits_bytes_in_flight_to_the_bandwidth_delay_product() {
+        let mut conditions = config(Delay::from_millis(100)).outbound;
+        assert_eq!(in_flight_limit(&conditions), MAX_BYTES_IN_FLIGHT);
+
+        conditions.max_bandwidth = "80Mbit".parse().unwrap();
+        assert_eq!(in_flight_limit(&conditions), 2_000_000);
+
+        conditions.max_bandwidth = "1Mbit".parse().unwrap();
+        assert_eq!(in_flight_limit(&conditions), MIN_BYTES_IN_FLIGHT);
+    }
+}
@@ -0,0 +1,457 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use tracing::{debug, error, info};
+
+use super::proxy::{
+    single_rule, DelayLine, FlowLinks, Link, Scheduler, SharedConditions, POLL_INTERVAL,
+};
+use super::ShaperBackend;
+use crate::{ApplyConfig, Direction, ShapingRule, TrafficShapingError};
+
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// How long a client may neither send nor receive a datagram before its
+/// upstream socket is closed, unless set with `with_idle_timeout`
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Tells the time the idle timeouts are measured with
+type Clock = Arc<dyn Fn() -> Instant + Send + Sync>;
+
+/// Relays UDP datagrams from a local port to an upstream address in
+/// userspace, impairing every datagram in both directions. Needs no
+/// privileges, but clients have to send to the listening address instead
+/// of the upstream one.
+///
+/// Datagrams sent by clients get the outbound conditions, and replies from
+/// upstream the inbound ones, as if the clients ran on the shaped host.
+///
+/// Only a single rule is supported, and the interface, address, port
+/// and protocol filters of its config are ignored: every datagram going
+/// through the relay is shaped. Clients share the link unless the flow
+/// mask of the rule includes their source address or port, as set when
+/// the relay starts.
+///
+/// Each client gets an upstream socket of its own, closed once the client
+/// has been idle for the idle timeout. A client sending again afterwards
+/// gets a new socket, and so a new source port upstream.
+pub struct UdpProxyBackend {
+    listen: SocketAddr,
+    upstream: SocketAddr,
+    idle_timeout: Duration,
+    clock: Clock,
+    rule: String,
+    conditions: SharedConditions,
+    relay: Option<Relay>,
+}
+
+struct Relay {
+    local_addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl UdpProxyBackend {
+    pub fn new(listen: SocketAddr, upstream: SocketAddr) -> Self {
+        Self {
+            listen,
+            upstream,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            clock: Arc::new(Instant::now),
+            rule: String::new(),
+            conditions: Arc::new(RwLock::new(ApplyConfig::default())),
+            relay: None,
+        }
+    }
+
+    /// Closes the upstream socket of a client after the given time without
+    /// any datagram to or from it, instead of a minute
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Measures the idle timeouts with the given clock instead of the
+    /// system one
+    #[cfg(test)]
+    fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Returns the address the relay listens on once enabled, which tells
+    /// the port picked by the system when listening on port 0
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.relay.as_ref().map(|relay| relay.local_addr)
+    }
+}
+
+impl ShaperBackend for UdpProxyBackend {
+    fn enable(&mut self, rules: &[ShapingRule]) -> Result<(), TrafficShapingError> {
+        let rule = single_rule(rules)?;
+        let config = ApplyConfig::from(&rule.config);
+        check_supported(&config)?;
+        self.rule = rule.name.clone();
+        *self.conditions.write().unwrap() = config;
+
+        // Only update the conditions if the relay is already running
+        if self.relay.is_some() {
+            return Ok(());
+        }
+
+        let socket = Arc::new(UdpSocket::bind(self.listen)?);
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+        let local_addr = socket.local_addr()?;
+
+        let (upstream_tx, upstream_line) = DelayLine::spawn(
+            "udp-proxy-upstream",
+            |(upstream, payload): (Arc<UdpSocket>, Vec<u8>)| {
+                let _ = upstream.send(&payload);
+            },
+        )?;
+
+        let downstream_socket = socket.clone();
+        let (downstream_tx, downstream_line) = DelayLine::spawn(
+            "udp-proxy-downstream",
+            move |(client, payload): (SocketAddr, Vec<u8>)| {
+                let _ = downstream_socket.send_to(&payload, client);
+            },
+        )?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut sessions = Sessions {
+            upstream: self.upstream,
+            idle_timeout: self.idle_timeout,
+            clock: self.clock.clone(),
+            stop: stop.clone(),
+            outbound: FlowLinks::new(
+                self.conditions.clone(),
+                Direction::Outbound,
+                rule.config.flow_mask,
+            ),
+            inbound: FlowLinks::new(
+                self.conditions.clone(),
+                Direction::Inbound,
+                rule.config.flow_mask,
+            ),
+            sender: downstream_tx,
+            clients: HashMap::new(),
+            closing: Vec::new(),
+            last_sweep: (self.clock)(),
+        };
+        let relay_stop = stop.clone();
+        let relay = thread::Builder::new()
+            .name("udp-proxy-relay".to_string())
+            .spawn(move || {
+                let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+                while !relay_stop.load(Ordering::Relaxed) {
+                    // Also runs on the read timeouts, while no client sends,
+                    // and before a client idle for too long gets its session
+                    let received = socket.recv_from(&mut buf);
+                    sessions.close_idle();
+
+                    let (len, client) = match received {
+                        Ok(received) => received,
+                        Err(e) if is_transient(&e) => continue,
+                        Err(e) => {
+                            error!("udp proxy failed to receive: {}", e);
+                            break;
+                        }
+                    };
+
+                    let session = match sessions.get_or_open(client) {
+                        Ok(session) => session,
+                        Err(e) => {
+                            error!("udp proxy failed to open session for {}: {}", client, e);
+                            continue;
+                        }
+                    };
+
+                    let copies = session.link.lock().unwrap().transmit(&buf[..len]);
+                    for (deliver_at, payload) in copies {
+                        let _ = upstream_tx.send((deliver_at, (session.socket.clone(), payload)));
+                    }
+                }
+                sessions.join();
+            })?;
+
+        info!("udp proxy relaying {} to {}", local_addr, self.upstream);
+        self.relay = Some(Relay {
+            local_addr,
+            stop,
+            handles: vec![relay, upstream_line, downstream_line],
+        });
+
+        Ok(())
+    }
+
+    fn apply(&mut self, rule: &str, config: &ApplyConfig) -> Result<(), TrafficShapingError> {
+        if rule != self.rule {
+            return Err(TrafficShapingError::UnknownRule(rule.to_string()));
+        }
+        check_supported(config)?;
+        *self.conditions.write().unwrap() = config.clone();
+        Ok(())
+    }
+
+    fn is_active(&self) -> Result<bool, TrafficShapingError> {
+        Ok(self.relay.is_some())
+    }
+
+    fn requires_privileges(&self) -> bool {
+        false
+    }
+
+    fn cleanup(&mut self) -> Result<(), TrafficShapingError> {
+        if let Some(relay) = self.relay.take() {
+            relay.stop.store(true, Ordering::Relaxed);
+            for handle in relay.handles {
+                let _ = handle.join();
+            }
+            info!("udp proxy stopped");
+        }
+        Ok(())
+    }
+}
+
+/// Fails on ECN, as the proxy relays payloads and cannot mark the IP
+/// header of the datagrams it drops
+fn check_supported(config: &ApplyConfig) -> Result<(), TrafficShapingError> {
+    if config.inbound.ecn || config.outbound.ecn {
+        return Err(TrafficShapingError::Unsupported(
+            "ECN on the UDP proxy".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// The upstream sockets of the relay, one per client address so replies
+/// can be routed back to the client that caused them
+struct Sessions {
+    upstream: SocketAddr,
+    idle_timeout: Duration,
+    clock: Clock,
+    stop: Arc<AtomicBool>,
+    /// Links of the datagrams of the clients, and of the replies to them
+    outbound: FlowLinks,
+    inbound: FlowLinks,
+    sender: Scheduler<(SocketAddr, Vec<u8>)>,
+    clients: HashMap<SocketAddr, ClientSession>,
+    /// Threads of the sessions closed, until they notice
+    closing: Vec<JoinHandle<()>>,
+    last_sweep: Instant,
+}
+
+/// The upstream socket of a client, and the thread relaying the replies
+/// it receives
+struct ClientSession {
+    socket: Arc<UdpSocket>,
+    /// Link of the datagrams of the client, held so its flow outlives the
+    /// datagrams in flight
+    link: Arc<Mutex<Link>>,
+    /// When a datagram last went through the session, in either direction
+    last_active: Arc<Mutex<Instant>>,
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+impl Sessions {
+    /// Returns the session of a client, opening it on its first datagram
+    fn get_or_open(&mut self, client: SocketAddr) -> std::io::Result<&ClientSession> {
+        if !self.clients.contains_key(&client) {
+            let session = self.open(client)?;
+            self.clients.insert(client, session);
+        }
+
+        let session = &self.clients[&client];
+        *session.last_active.lock().unwrap() = (self.clock)();
+        Ok(session)
+    }
+
+    fn open(&mut self, client: SocketAddr) -> std::io::Result<ClientSession> {
+        let bind_addr = match self.upstream {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        };
+        let socket = Arc::new(UdpSocket::bind(bind_addr)?);
+        socket.connect(self.upstream)?;
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+
+        let last_active = Arc::new(Mutex::new((self.clock)()));
+        let session_stop = Arc::new(AtomicBool::new(false));
+
+        let reader = socket.clone();
+        let active = last_active.clone();
+        let clock = self.clock.clone();
+        let stop = self.stop.clone();
+        let closed = session_stop.clone();
+        let link = self.inbound.get(client);
+        let sender = self.sender.clone();
+        let handle = thread::Builder::new()
+            .name(format!("udp-proxy-session-{}", client))
+            .spawn(move || {
+                let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+                while !stop.load(Ordering::Relaxed) && !closed.load(Ordering::Relaxed) {
+                    let len = match reader.recv(&mut buf) {
+                        Ok(len) => len,
+                        Err(e) if is_transient(&e) => continue,
+                        Err(e) => {
+                            error!("udp proxy failed to receive from upstream: {}", e);
+                            break;
+                        }
+                    };
+                    *active.lock().unwrap() = clock();
+
+                    let copies = link.lock().unwrap().transmit(&buf[..len]);
+                    for (deliver_at, payload) in copies {
+                        let _ = sender.send((deliver_at, (client, payload)));
+                    }
+                }
+            })?;
+
+        Ok(ClientSession {
+            socket,
+            link: self.outbound.get(client),
+            last_active,
+            stop: session_stop,
+            handle,
+        })
+    }
+
+    /// Closes the sessions idle for the idle timeout, at most once per
+    /// poll interval. Their threads are joined once they stopped, so the
+    /// relay never waits for them.
+    fn close_idle(&mut self) {
+        let now = (self.clock)();
+        if now.duration_since(self.last_sweep) < POLL_INTERVAL {
+            return;
+        }
+        self.last_sweep = now;
+
+        let idle: Vec<SocketAddr> = self
+            .clients
+            .iter()
+            .filter(|(_, session)| {
+                now.duration_since(*session.last_active.lock().unwrap()) >= self.idle_timeout
+            })
+            .map(|(client, _)| *client)
+            .collect();
+        for client in idle {
+            let session = self.clients.remove(&client).unwrap();
+            session.stop.store(true, Ordering::Relaxed);
+            self.closing.push(session.handle);
+            debug!("udp proxy closed the idle session of {}", client);
+        }
+
+        let (stopped, running) = self
+            .closing
+            .drain(..)
+            .partition(|handle| handle.is_finished());
+        self.closing = running;
+        for handle in stopped {
+            let _ = handle.join();
+        }
+
+        // Flows outlive their sessions until their threads are joined
+        self.outbound.prune();
+        self.inbound.prune();
+    }
+
+    fn join(self) {
+        let sessions = self.clients.into_values().map(|session| session.handle);
+        for handle in sessions.chain(self.closing) {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Errors that do not end the relay: read timeouts, and the ICMP port
+/// unreachable reported on a connected socket while upstream is down
+fn is_transient(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::ConnectionRefused
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Bandwidth, Delay, Output, Protocol, TrafficConfig};
+
+    /// Echoes every datagram back, reporting the address it came from
+    fn echo_upstream() -> (SocketAddr, std::sync::mpsc::Receiver<SocketAddr>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let (peers_tx, peers) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0u8; 64];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf) {
+                let _ = socket.send_to(&buf[..len], peer);
+                if peers_tx.send(peer).is_err() {
+                    break;
+                }
+            }
+        });
+        (addr, peers)
+    }
+
+    fn round_trip(client: &UdpSocket, payload: &[u8]) {
+        client.send(payload).unwrap();
+        let mut buf = [0u8; 64];
+        let len = client.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], payload);
+    }
+
+    #[test]
+    fn closes_idle_sessions() {
+        let (upstream, peers) = echo_upstream();
+        // A clock moved forward by the test
+        let start = Instant::now();
+        let elapsed = Arc::new(Mutex::new(Duration::ZERO));
+        let clock_elapsed = elapsed.clone();
+        let mut backend = UdpProxyBackend::new("127.0.0.1:0".parse().unwrap(), upstream)
+            .with_idle_timeout(Duration::from_secs(300))
+            .with_clock(Arc::new(move || start + *clock_elapsed.lock().unwrap()));
+        let config = TrafficConfig::new(
+            0.0,
+            Delay::ZERO,
+            Bandwidth::UNLIMITED,
+            Protocol::Udp,
+            None,
+            None,
+            Output::None,
+        )
+        .unwrap();
+        backend
+            .enable(&[ShapingRule {
+                name: "default".to_string(),
+                config,
+            }])
+            .unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.connect(backend.local_addr().unwrap()).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        // An active client keeps its upstream socket
+        round_trip(&client, b"first");
+        round_trip(&client, b"second");
+        let first = peers.recv().unwrap();
+        assert_eq!(peers.recv().unwrap(), first);
+
+        // An idle one gets a new socket
+        *elapsed.lock().unwrap() = Duration::from_secs(301);
+        round_trip(&client, b"third");
+        assert_ne!(peers.recv().unwrap(), first);
+
+        backend.cleanup().unwrap();
+    }
+}
@@ -1,67 +1,197 @@
-use std::io::Write;
-use std::process::Command;
+use std::sync::Arc;
 
-use tempfile::NamedTempFile;
-use tracing::info;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
 
-use crate::TrafficShapingError;
+use crate::pf_rule::PfRule;
+use crate::rules::PfRuleset;
+use crate::runner::{CommandRunner, Invocation};
+use crate::{
+    DelayDistribution, DummynetStatus, FlowMask, LinkConditions, QueueSize, TrafficShapingError,
+};
 
-pub(crate) struct PfctlCommands;
-pub(crate) struct DnctlCommands;
+pub(crate) struct PfctlCommands {
+    runner: Arc<dyn CommandRunner>,
+}
+
+pub(crate) struct DnctlCommands {
+    runner: Arc<dyn CommandRunner>,
+}
+
+pub(crate) struct TcCommands {
+    runner: Arc<dyn CommandRunner>,
+}
 
 // pfctl - packet filter control
 impl PfctlCommands {
-    /// Loads PF rules from a file
-    pub fn load_rules(rules: &str, anchor_name: Option<&str>) -> Result<(), TrafficShapingError> {
-        let mut temp_file = NamedTempFile::new()?;
-        temp_file.write_all(rules.as_bytes())?;
+    pub fn new(runner: Arc<dyn CommandRunner>) -> Self {
+        Self { runner }
+    }
 
-        let mut pfctl = Command::new("pfctl");
+    /// Loads PF rules, read by pfctl from its standard input
+    pub fn load_rules(
+        &self,
+        rules: &str,
+        anchor_name: Option<&str>,
+    ) -> Result<(), TrafficShapingError> {
+        let mut pfctl = Invocation::new("pfctl");
         if let Some(anchor_name) = anchor_name {
-            pfctl.arg("-a").arg(anchor_name);
+            pfctl = pfctl.arg("-a").arg(anchor_name);
         }
-        let output = pfctl.arg("-f").arg(temp_file.path()).output()?;
+        self.runner
+            .run_checked(&pfctl.arg("-f").arg("-").stdin(rules))?;
 
-        if !output.status.success() {
-            return Err(TrafficShapingError::CommandError(
-                String::from_utf8_lossy(&output.stderr).to_string(),
-            ));
+        Ok(())
+    }
+
+    /// Lists the main ruleset, in a form pfctl can load back. Loading a
+    /// ruleset resets the options it leaves out and drops the tables it
+    /// does not define, so both are listed along with the rules.
+    pub fn snapshot(&self) -> Result<PfRuleset, TrafficShapingError> {
+        Ok(PfRuleset {
+            options: self.options()?,
+            tables: self.tables()?,
+            translation: self.show("nat")?,
+            dummynet: self.show("dummynet")?,
+            filter: self.show("rules")?,
+        })
+    }
+
+    fn show(&self, modifier: &str) -> Result<String, TrafficShapingError> {
+        let output = self
+            .runner
+            .run_checked(&Invocation::new("pfctl").arg("-s").arg(modifier))?;
+
+        Ok(output.stdout)
+    }
+
+    /// Lists the options pfctl shows, as `set` statements: the skipped
+    /// interfaces, the timeouts and the memory limits
+    fn options(&self) -> Result<String, TrafficShapingError> {
+        let mut options = String::new();
+
+        // Listed as `lo0 (skip)`
+        let interfaces = self
+            .runner
+            .run_checked(&Invocation::new("pfctl").args(["-v", "-s", "Interfaces"]))?
+            .stdout;
+        let skipped: Vec<&str> = interfaces
+            .lines()
+            .filter_map(|line| line.trim().strip_suffix("(skip)"))
+            .map(str::trim)
+            .collect();
+        if !skipped.is_empty() {
+            options.push_str(&format!("set skip on {{ {} }}\n", skipped.join(" ")));
         }
 
-        Ok(())
+        // Listed as `tcp.first  120s` or `adaptive.start  6000 states`
+        for line in self.show("timeouts")?.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if let [name, value, ..] = fields[..] {
+                let value = value.trim_end_matches('s');
+                if value.parse::<u32>().is_ok() {
+                    options.push_str(&format!("set timeout {} {}\n", name, value));
+                }
+            }
+        }
+
+        // Listed as `states  hard limit  10000`
+        for line in self.show("memory")?.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if let [name, "hard", "limit", value] = fields[..] {
+                options.push_str(&format!("set limit {} {}\n", name, value));
+            }
+        }
+
+        Ok(options)
     }
 
-    /// Restores original PF rules from /etc/pf.conf
-    pub fn restore_original_rules() -> Result<(), TrafficShapingError> {
-        let output = Command::new("pfctl")
-            .arg("-f")
-            .arg("/etc/pf.conf")
-            .output()?;
+    /// Lists the tables of the main ruleset with their addresses, as
+    /// `table` statements
+    fn tables(&self) -> Result<String, TrafficShapingError> {
+        let mut tables = String::new();
+        for name in self.show("Tables")?.lines().map(str::trim) {
+            if name.is_empty() {
+                continue;
+            }
+            let output = self
+                .runner
+                .run_checked(&Invocation::new("pfctl").args(["-t", name, "-T", "show"]))?;
+            let addresses: Vec<&str> = output
+                .stdout
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.is_empty())
+                .collect();
 
-        if !output.status.success() {
-            return Err(TrafficShapingError::CommandError(
-                String::from_utf8_lossy(&output.stderr).to_string(),
-            ));
+            if addresses.is_empty() {
+                tables.push_str(&format!("table <{}> persist\n", name));
+            } else {
+                tables.push_str(&format!(
+                    "table <{}> persist {{ {} }}\n",
+                    name,
+                    addresses.join(", ")
+                ));
+            }
         }
+        Ok(tables)
+    }
 
-        Ok(())
+    /// Lists the dummynet rules of an anchor, skipping the lines that are
+    /// not rules
+    pub fn anchor_rules(&self, anchor_name: &str) -> Result<Vec<PfRule>, TrafficShapingError> {
+        let output = self.runner.run_checked(
+            &Invocation::new("pfctl")
+                .arg("-a")
+                .arg(anchor_name)
+                .args(["-s", "dummynet"]),
+        )?;
+
+        Ok(output
+            .stdout
+            .lines()
+            .filter_map(|line| line.parse().ok())
+            .collect())
     }
 
-    /// Enables PF with reference counting
-    pub fn enable() -> Result<(), TrafficShapingError> {
-        let _ = Command::new("pfctl").arg("-e").output()?;
+    /// Removes every rule of an anchor
+    pub fn flush_anchor(&self, anchor_name: &str) -> Result<(), TrafficShapingError> {
+        self.runner.run_checked(
+            &Invocation::new("pfctl")
+                .arg("-a")
+                .arg(anchor_name)
+                .args(["-F", "all"]),
+        )?;
+
         Ok(())
     }
 
-    /// Disables PF
-    pub fn disable() -> Result<(), TrafficShapingError> {
-        let output = Command::new("pfctl").arg("-d").output()?;
+    /// Takes a reference on PF, enabling it if nobody holds one yet, and
+    /// returns the token releasing it
+    pub fn enable(&self) -> Result<String, TrafficShapingError> {
+        let output = self
+            .runner
+            .run_checked(&Invocation::new("pfctl").arg("-E"))?;
 
-        if !output.status.success() {
-            return Err(TrafficShapingError::CommandError(
-                String::from_utf8_lossy(&output.stderr).to_string(),
-            ));
-        }
+        // pfctl prints `Token : <token>` on stderr, along with other messages
+        output
+            .stderr
+            .lines()
+            .chain(output.stdout.lines())
+            .filter_map(|line| line.split_once(':'))
+            .find(|(key, _)| key.trim() == "Token")
+            .map(|(_, token)| token.trim().to_string())
+            .filter(|token| !token.is_empty())
+            .ok_or_else(|| {
+                TrafficShapingError::CommandError("pfctl -E printed no token".to_string())
+            })
+    }
+
+    /// Releases a reference taken by `enable`. PF is disabled once the last
+    /// reference is released.
+    pub fn release(&self, token: &str) -> Result<(), TrafficShapingError> {
+        self.runner
+            .run_checked(&Invocation::new("pfctl").arg("-X").arg(token))?;
 
         Ok(())
     }
@@ -69,63 +199,712 @@ impl PfctlCommands {
 
 // dnctl - dummynet control
 impl DnctlCommands {
-    /// Checks if a pipe exists
-    pub fn pipe_exists(pipe_num: u32) -> Result<bool, TrafficShapingError> {
-        let output = Command::new("dnctl").arg("show").output()?;
+    pub fn new(runner: Arc<dyn CommandRunner>) -> Self {
+        Self { runner }
+    }
 
-        if !output.status.success() {
-            return Err(TrafficShapingError::CommandError(
-                String::from_utf8_lossy(&output.stderr).to_string(),
-            ));
-        }
+    /// Lists the configured pipes and queues
+    pub fn show(&self) -> Result<DummynetStatus, TrafficShapingError> {
+        let output = self
+            .runner
+            .run_checked(&Invocation::new("dnctl").arg("show"))?;
+
+        Ok(DummynetStatus::parse(&output.stdout))
+    }
 
-        let output_str = String::from_utf8_lossy(&output.stdout);
-        Ok(output_str.contains(&format!("pipe {} ", pipe_num)))
+    /// Lists the numbers of the configured pipes
+    pub fn list_pipes(&self) -> Result<Vec<u32>, TrafficShapingError> {
+        Ok(self.show()?.pipes.iter().map(|pipe| pipe.number).collect())
     }
 
-    /// Creates or updates a pipe with specified configuration
+    /// Creates or updates a pipe with specified configuration. With a
+    /// non-empty mask, dummynet creates a pipe with this configuration for
+    /// every flow the mask tells apart.
     pub fn configure_pipe(
+        &self,
         pipe_num: u32,
-        bandwidth: Option<u64>,
-        delay: Option<u32>,
-        plr: Option<f32>,
+        conditions: &LinkConditions,
+        mask: &FlowMask,
     ) -> Result<(), TrafficShapingError> {
-        let mut cmd = Command::new("dnctl");
-        cmd.arg("pipe").arg(pipe_num.to_string()).arg("config");
+        let cmd = Invocation::new("dnctl")
+            .arg("pipe")
+            .arg(pipe_num.to_string())
+            .arg("config")
+            .arg("bw")
+            .arg(format!("{}bit/s", conditions.max_bandwidth.bps()))
+            .arg("delay")
+            .arg(format!("{}ms", conditions.latency.as_millis()))
+            .arg("plr")
+            // Convert percentage to ratio
+            .arg((conditions.packet_loss / 100.0).to_string())
+            .args(Self::queue_options(conditions))
+            .args(Self::mask_options(mask));
 
-        if let Some(bw) = bandwidth {
-            cmd.arg("bw").arg(format!("{}bit/s", bw));
+        self.runner.run_checked(&cmd)?;
+
+        Ok(())
+    }
+
+    /// Lists the numbers of the configured queues
+    pub fn list_queues(&self) -> Result<Vec<u32>, TrafficShapingError> {
+        Ok(self
+            .show()?
+            .queues
+            .iter()
+            .map(|queue| queue.number)
+            .collect())
+    }
+
+    /// Creates or updates a queue feeding a pipe, which serves its queues
+    /// in proportion to their weights. With a non-empty mask, dummynet
+    /// creates a queue with this weight for every flow the mask tells apart.
+    pub fn configure_queue(
+        &self,
+        queue_num: u32,
+        pipe_num: u32,
+        weight: u32,
+        conditions: &LinkConditions,
+        mask: &FlowMask,
+    ) -> Result<(), TrafficShapingError> {
+        let cmd = Invocation::new("dnctl")
+            .arg("queue")
+            .arg(queue_num.to_string())
+            .arg("config")
+            .arg("pipe")
+            .arg(pipe_num.to_string())
+            .arg("weight")
+            .arg(weight.to_string())
+            .args(Self::queue_options(conditions))
+            .args(Self::mask_options(mask));
+
+        self.runner.run_checked(&cmd)?;
+
+        Ok(())
+    }
+
+    /// Renders the size and the RED parameters of a queue
+    fn queue_options(conditions: &LinkConditions) -> Vec<String> {
+        let mut options = Vec::new();
+
+        match conditions.queue {
+            Some(QueueSize::Slots(slots)) => {
+                options.extend(["queue".to_string(), slots.to_string()])
+            }
+            Some(QueueSize::Bytes(bytes)) => {
+                options.extend(["queue".to_string(), format!("{}bytes", bytes)])
+            }
+            None => {}
         }
 
-        if let Some(d) = delay {
-            cmd.arg("delay").arg(format!("{}ms", d));
+        if let Some(red) = &conditions.red {
+            options.push(if red.gentle { "gred" } else { "red" }.to_string());
+            options.push(format!(
+                "{}/{}/{}/{}",
+                red.weight, red.min_threshold, red.max_threshold, red.max_probability
+            ));
+        }
+
+        options
+    }
+
+    /// Renders a flow mask
+    fn mask_options(mask: &FlowMask) -> Vec<&'static str> {
+        if *mask == FlowMask::five_tuple() {
+            return vec!["mask", "all"];
+        }
+        if mask.is_empty() {
+            return Vec::new();
         }
 
-        if let Some(p) = plr {
-            cmd.arg("plr").arg(p.to_string());
+        let mut options = vec!["mask"];
+        if mask.src_address {
+            options.extend(["src-ip", "0xffffffff", "src-ip6", "/128"]);
+        }
+        if mask.dst_address {
+            options.extend(["dst-ip", "0xffffffff", "dst-ip6", "/128"]);
+        }
+        if mask.src_port {
+            options.extend(["src-port", "0xffff"]);
         }
+        if mask.dst_port {
+            options.extend(["dst-port", "0xffff"]);
+        }
+        if mask.protocol {
+            options.extend(["proto", "0xff"]);
+        }
+        options
+    }
 
-        let output = cmd.output()?;
+    /// Deletes the given pipes, leaving the others in place
+    pub fn delete_pipes(&self, pipes: &[u32]) -> Result<(), TrafficShapingError> {
+        self.delete("pipe", pipes)
+    }
 
-        if !output.status.success() {
-            return Err(TrafficShapingError::CommandError(
-                String::from_utf8_lossy(&output.stderr).to_string(),
-            ));
+    /// Deletes the given queues, leaving the others in place
+    pub fn delete_queues(&self, queues: &[u32]) -> Result<(), TrafficShapingError> {
+        self.delete("queue", queues)
+    }
+
+    fn delete(&self, kind: &str, numbers: &[u32]) -> Result<(), TrafficShapingError> {
+        if numbers.is_empty() {
+            return Ok(());
         }
+        self.runner.run_checked(
+            &Invocation::new("dnctl")
+                .arg(kind)
+                .arg("delete")
+                .args(numbers.iter().map(u32::to_string)),
+        )?;
 
         Ok(())
     }
+}
 
-    /// Flushes all pipes
-    pub fn flush_pipes() -> Result<(), TrafficShapingError> {
-        let output = Command::new("dnctl").arg("-q").arg("flush").output()?;
+/// Handle of the root `prio` qdisc installed on every shaped device
+pub(crate) const TC_ROOT_HANDLE: &str = "1:";
+/// Bands of the `prio` qdisc used by the default priomap
+const TC_DEFAULT_BANDS: usize = 3;
+/// Most rules a device can carry, as `prio` has at most 16 bands
+pub(crate) const TC_MAX_RULES: usize = 16 - TC_DEFAULT_BANDS;
+/// Prefix of the ifb devices used to shape ingress traffic
+pub(crate) const TC_IFB_PREFIX: &str = "tsifb";
 
-        if !output.status.success() {
-            return Err(TrafficShapingError::CommandError(
-                String::from_utf8_lossy(&output.stderr).to_string(),
-            ));
+/// The root qdisc of a device before it was shaped, as listed by
+/// `tc qdisc show`, like `qdisc fq_codel 0: root refcnt 2 limit 10240p`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct RootQdisc {
+    pub kind: String,
+    pub handle: String,
+    /// Parameters as listed, without the reference count
+    pub options: Vec<String>,
+}
+
+impl RootQdisc {
+    /// Checks if the kernel attached the qdisc by default, in which case
+    /// deleting the root attaches it again
+    pub fn is_default(&self) -> bool {
+        self.handle == "0:"
+    }
+}
+
+/// Class of the `prio` band that the traffic of a rule is steered into
+fn tc_shaped_class(rule_idx: usize) -> String {
+    format!("1:{}", TC_DEFAULT_BANDS + rule_idx + 1)
+}
+
+/// Handle of the `netem` qdisc attached to the band of a rule
+fn tc_netem_handle(rule_idx: usize) -> String {
+    format!("{}:", 10 + rule_idx)
+}
+
+// tc - linux traffic control
+impl TcCommands {
+    pub fn new(runner: Arc<dyn CommandRunner>) -> Self {
+        Self { runner }
+    }
+
+    /// Lists the network devices present on the system
+    pub fn list_devices(&self) -> Result<Vec<String>, TrafficShapingError> {
+        let output = self
+            .runner
+            .run_checked(&Invocation::new("ip").args(["-o", "link", "show"]))?;
+
+        // Lines look like `2: eth0@if5: <BROADCAST,MULTICAST,UP> mtu 1500 ...`
+        let mut devices: Vec<String> = output
+            .stdout
+            .lines()
+            .filter_map(|line| line.split(": ").nth(1))
+            .map(|name| name.split('@').next().unwrap_or(name).to_string())
+            .collect();
+        devices.sort();
+        Ok(devices)
+    }
+
+    /// Installs the root prio qdisc with an extra band per rule. The default
+    /// priomap never selects the extra bands, so only traffic matched by a
+    /// filter is impaired.
+    pub fn install_root(&self, dev: &str, rules: usize) -> Result<(), TrafficShapingError> {
+        self.runner.run_checked(
+            &Invocation::new("tc")
+                .args(["qdisc", "replace", "dev", dev])
+                .args(["root", "handle", TC_ROOT_HANDLE])
+                .arg("prio")
+                .arg("bands")
+                .arg((TC_DEFAULT_BANDS + rules).to_string())
+                .arg("priomap")
+                .args(["1", "2", "2", "2", "1", "2", "0", "0"])
+                .args(["1", "1", "1", "1", "1", "1", "1", "1"]),
+        )?;
+
+        Ok(())
+    }
+
+    /// Creates or updates the netem qdisc of the band of a rule
+    pub fn configure_netem(
+        &self,
+        dev: &str,
+        rule_idx: usize,
+        conditions: &LinkConditions,
+    ) -> Result<(), TrafficShapingError> {
+        let mut cmd = Invocation::new("tc")
+            .args(["qdisc", "replace", "dev", dev])
+            .arg("parent")
+            .arg(tc_shaped_class(rule_idx))
+            .arg("handle")
+            .arg(tc_netem_handle(rule_idx))
+            .arg("netem");
+
+        cmd = cmd.arg("delay").arg(conditions.latency.to_string());
+        if let Some(jitter) = &conditions.jitter {
+            cmd = cmd.arg(jitter.deviation.to_string());
+            if jitter.correlation > 0.0 {
+                cmd = cmd.arg(format!("{}%", jitter.correlation));
+            }
+            // Uniform is the default of netem and has no table to name
+            match jitter.distribution {
+                DelayDistribution::Uniform => {}
+                DelayDistribution::Normal => cmd = cmd.args(["distribution", "normal"]),
+                DelayDistribution::Pareto => cmd = cmd.args(["distribution", "pareto"]),
+            }
         }
 
+        cmd = match &conditions.burst_loss {
+            // netem takes the loss probabilities of the states as 1-h and 1-k
+            Some(model) => cmd.args(["loss", "gemodel"]).args([
+                format!("{}%", model.to_bad),
+                format!("{}%", model.to_good),
+                format!("{}%", model.bad_loss),
+                format!("{}%", model.good_loss),
+            ]),
+            None => cmd.arg("loss").arg(format!("{}%", conditions.packet_loss)),
+        };
+
+        // netem only reorders packets if it delays the others
+        if let Some(reorder) = &conditions.reorder {
+            cmd = cmd
+                .arg("reorder")
+                .arg(format!("{}%", reorder.probability))
+                .arg(format!("{}%", reorder.correlation))
+                .arg("gap")
+                .arg(reorder.gap.to_string());
+        }
+
+        if conditions.duplicate > 0.0 {
+            cmd = cmd
+                .arg("duplicate")
+                .arg(format!("{}%", conditions.duplicate));
+        }
+
+        if conditions.corrupt > 0.0 {
+            cmd = cmd.arg("corrupt").arg(format!("{}%", conditions.corrupt));
+        }
+
+        // netem counts packets in its queue, including the ones being delayed
+        if let Some(QueueSize::Slots(slots)) = conditions.queue {
+            cmd = cmd.arg("limit").arg(slots.to_string());
+        }
+
+        // Losses become congestion experienced marks for ECN capable packets
+        if conditions.ecn {
+            cmd = cmd.arg("ecn");
+        }
+
+        // dummynet treats a bandwidth of 0 as unlimited, netem has no such value
+        if !conditions.max_bandwidth.is_unlimited() {
+            cmd = cmd
+                .arg("rate")
+                .arg(format!("{}bit", conditions.max_bandwidth.bps()));
+        }
+
+        self.runner.run_checked(&cmd)?;
+
+        Ok(())
+    }
+
+    /// Adds a filter steering matching packets into the band of a rule
+    pub fn add_filter(
+        &self,
+        dev: &str,
+        rule_idx: usize,
+        filter: &str,
+    ) -> Result<(), TrafficShapingError> {
+        self.runner.run_checked(
+            &Invocation::new("tc")
+                .args(["filter", "add", "dev", dev, "parent", TC_ROOT_HANDLE])
+                .args(filter.split_whitespace())
+                .arg("flowid")
+                .arg(tc_shaped_class(rule_idx)),
+        )?;
+
+        Ok(())
+    }
+
+    /// Creates an ifb device and redirects all ingress traffic of `dev` to it
+    pub fn redirect_ingress(&self, dev: &str, ifb: &str) -> Result<(), TrafficShapingError> {
+        self.runner.run_checked(
+            &Invocation::new("ip").args(["link", "add", "name", ifb, "type", "ifb"]),
+        )?;
+        self.runner
+            .run_checked(&Invocation::new("ip").args(["link", "set", "dev", ifb, "up"]))?;
+        self.runner.run_checked(
+            &Invocation::new("tc")
+                .args(["qdisc", "replace", "dev", dev, "handle", "ffff:", "ingress"]),
+        )?;
+        self.runner.run_checked(
+            &Invocation::new("tc")
+                .args(["filter", "add", "dev", dev])
+                .args(["parent", "ffff:", "protocol", "all"])
+                .args(["u32", "match", "u32", "0", "0"])
+                .args(["action", "mirred", "egress", "redirect", "dev", ifb]),
+        )?;
+
+        Ok(())
+    }
+
+    fn show_qdiscs(&self, dev: &str) -> Result<String, TrafficShapingError> {
+        let output = self
+            .runner
+            .run_checked(&Invocation::new("tc").args(["qdisc", "show", "dev", dev]))?;
+        Ok(output.stdout)
+    }
+
+    /// Checks if the device carries the qdiscs installed by the shaper: its
+    /// root `prio` and the netem of the band of the first rule
+    pub fn is_shaped(&self, dev: &str) -> Result<bool, TrafficShapingError> {
+        let qdiscs = self.show_qdiscs(dev)?;
+
+        Ok(
+            qdiscs.contains(&format!("qdisc prio {} root ", TC_ROOT_HANDLE))
+                && qdiscs.contains(&format!(
+                    "qdisc netem {} parent {} ",
+                    tc_netem_handle(0),
+                    tc_shaped_class(0)
+                )),
+        )
+    }
+
+    /// Returns the root qdisc of a device, if it lists one
+    pub fn root_qdisc(&self, dev: &str) -> Result<Option<RootQdisc>, TrafficShapingError> {
+        let qdiscs = self.show_qdiscs(dev)?;
+
+        Ok(qdiscs.lines().find_map(|line| {
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            let ["qdisc", kind, handle, "root", rest @ ..] = tokens.as_slice() else {
+                return None;
+            };
+            let options = match rest {
+                ["refcnt", _, options @ ..] => options,
+                options => options,
+            };
+            Some(RootQdisc {
+                kind: kind.to_string(),
+                handle: handle.to_string(),
+                options: options.iter().map(ToString::to_string).collect(),
+            })
+        }))
+    }
+
+    /// Returns the kind of the ingress or clsact qdisc of a device, if it
+    /// has one. Both are attached at `ffff:fff1`.
+    pub fn ingress_qdisc(&self, dev: &str) -> Result<Option<String>, TrafficShapingError> {
+        let qdiscs = self.show_qdiscs(dev)?;
+
+        Ok(qdiscs.lines().find_map(|line| {
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            let ["qdisc", kind, _, "parent", "ffff:fff1", ..] = tokens.as_slice() else {
+                return None;
+            };
+            Some(kind.to_string())
+        }))
+    }
+
+    /// Puts back the root qdisc a device had before it was shaped. A qdisc
+    /// the kernel attached by default is attached again by deleting the
+    /// root. Otherwise the qdisc is recreated with its listed parameters,
+    /// or its default ones if tc refuses them, as some are listed in
+    /// another syntax than the one it parses.
+    pub fn restore_root(&self, dev: &str, root: &RootQdisc) -> Result<(), TrafficShapingError> {
+        if root.is_default() {
+            self.runner
+                .run_checked(&Invocation::new("tc").args(["qdisc", "del", "dev", dev, "root"]))?;
+            return Ok(());
+        }
+
+        let replace = Invocation::new("tc")
+            .args(["qdisc", "replace", "dev", dev])
+            .args(["root", "handle", &root.handle, &root.kind]);
+        if self
+            .runner
+            .run(&replace.clone().args(&root.options))?
+            .is_success()
+        {
+            return Ok(());
+        }
+        warn!(
+            "failed to restore the parameters of the {} qdisc of {}, restoring its defaults",
+            root.kind, dev
+        );
+        self.runner.run_checked(&replace)?;
+
+        Ok(())
+    }
+
+    /// Removes the ingress qdisc of a device, with the redirection of its
+    /// traffic to an ifb device
+    pub fn remove_ingress(&self, dev: &str) -> Result<(), TrafficShapingError> {
+        // Not every device has an ingress qdisc, e.g. the ifb devices themselves
+        let _ = self
+            .runner
+            .run(&Invocation::new("tc").args(["qdisc", "del", "dev", dev, "ingress"]))?;
+
+        Ok(())
+    }
+
+    /// Removes the ingress and root qdiscs of a device
+    pub fn reset_device(&self, dev: &str) -> Result<(), TrafficShapingError> {
+        self.remove_ingress(dev)?;
+        self.runner
+            .run_checked(&Invocation::new("tc").args(["qdisc", "del", "dev", dev, "root"]))?;
+
         Ok(())
     }
+
+    /// Deletes a network device
+    pub fn delete_device(&self, dev: &str) -> Result<(), TrafficShapingError> {
+        self.runner
+            .run_checked(&Invocation::new("ip").args(["link", "del", "dev", dev]))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::{CommandOutput, FakeRunner};
+    use crate::{Bandwidth, Delay, GilbertElliott, Jitter, Red, Reorder};
+
+    #[test]
+    fn snapshot_lists_options_and_tables() {
+        let fake = Arc::new(FakeRunner::new());
+        fake.respond(
+            "pfctl -v -s Interfaces",
+            CommandOutput::success("all\nen0\nlo0 (skip)\nutun0 (skip)\n"),
+        );
+        fake.respond(
+            "pfctl -s timeouts",
+            CommandOutput::success(
+                "tcp.first                   120s\n\
+                 interval                     10s\n\
+                 adaptive.start             6000 states\n",
+            ),
+        );
+        fake.respond(
+            "pfctl -s memory",
+            CommandOutput::success(
+                "states        hard limit    10000\n\
+                 table-entries hard limit   200000\n",
+            ),
+        );
+        fake.respond(
+            "pfctl -s Tables",
+            CommandOutput::success("blocked\nempty\n"),
+        );
+        fake.respond(
+            "pfctl -t blocked -T show",
+            CommandOutput::success("   10.0.0.1\n   !192.168.1.0/24\n"),
+        );
+        fake.respond(
+            "pfctl -s nat",
+            CommandOutput::success("nat on en0 from 10.0.0.0/24 to any -> (en0)\n"),
+        );
+        fake.respond(
+            "pfctl -s dummynet",
+            CommandOutput::success("dummynet-anchor \"com.apple/*\" all\n"),
+        );
+        fake.respond(
+            "pfctl -s rules",
+            CommandOutput::success(
+                "block drop in quick from <blocked> to any\n\
+                 scrub in all fragment reassemble\n",
+            ),
+        );
+
+        let ruleset = PfctlCommands::new(fake.clone()).snapshot().unwrap();
+
+        assert_eq!(
+            ruleset.render(),
+            "set skip on { lo0 utun0 }\n\
+             set timeout tcp.first 120\n\
+             set timeout interval 10\n\
+             set timeout adaptive.start 6000\n\
+             set limit states 10000\n\
+             set limit table-entries 200000\n\
+             table <blocked> persist { 10.0.0.1, !192.168.1.0/24 }\n\
+             table <empty> persist\n\
+             scrub in all fragment reassemble\n\
+             nat on en0 from 10.0.0.0/24 to any -> (en0)\n\
+             dummynet-anchor \"com.apple/*\" all\n\
+             block drop in quick from <blocked> to any\n"
+        );
+    }
+
+    #[test]
+    fn snapshot_fails_if_a_listing_fails() {
+        let fake = Arc::new(FakeRunner::new());
+        fake.respond(
+            "pfctl -s Tables",
+            CommandOutput::failure(1, "pfctl: Operation not permitted"),
+        );
+
+        assert!(PfctlCommands::new(fake).snapshot().is_err());
+    }
+
+    /// Configures the netem qdisc of the first rule on eth0 and returns the
+    /// options passed to netem
+    fn netem_options(conditions: &LinkConditions) -> String {
+        let fake = Arc::new(FakeRunner::new());
+        TcCommands::new(fake.clone())
+            .configure_netem("eth0", 0, conditions)
+            .unwrap();
+        let lines = fake.command_lines();
+        assert_eq!(lines.len(), 1);
+        lines[0]
+            .strip_prefix("tc qdisc replace dev eth0 parent 1:4 handle 10: netem ")
+            .unwrap()
+            .to_string()
+    }
+
+    fn conditions() -> LinkConditions {
+        LinkConditions::new(0.0, Delay::from_millis(100), Bandwidth::UNLIMITED).unwrap()
+    }
+
+    #[test]
+    fn netem_takes_the_jitter_and_its_distribution() {
+        let mut jittery = conditions();
+        jittery.jitter =
+            Some(Jitter::new(Delay::from_millis(20), DelayDistribution::Uniform, 0.0).unwrap());
+        assert_eq!(netem_options(&jittery), "delay 100ms 20ms loss 0%");
+
+        jittery.jitter =
+            Some(Jitter::new(Delay::from_millis(20), DelayDistribution::Normal, 25.0).unwrap());
+        assert_eq!(
+            netem_options(&jittery),
+            "delay 100ms 20ms 25% distribution normal loss 0%"
+        );
+
+        jittery.jitter =
+            Some(Jitter::new("500us".parse().unwrap(), DelayDistribution::Pareto, 0.0).unwrap());
+        assert_eq!(
+            netem_options(&jittery),
+            "delay 100ms 500us distribution pareto loss 0%"
+        );
+    }
+
+    #[test]
+    fn netem_takes_the_gilbert_elliott_model() {
+        let mut bursty = conditions();
+        bursty.packet_loss = 1.0;
+        bursty.burst_loss = Some(GilbertElliott::new(5.0, 40.0, 90.0, 0.5).unwrap());
+        // The model replaces the independent loss
+        assert_eq!(
+            netem_options(&bursty),
+            "delay 100ms loss gemodel 5% 40% 90% 0.5%"
+        );
+    }
+
+    #[test]
+    fn netem_takes_reordering_duplication_and_corruption() {
+        let mut impaired = conditions();
+        impaired.reorder = Some(Reorder::new(25.0, 50.0, 5).unwrap());
+        impaired.duplicate = 1.5;
+        impaired.corrupt = 0.1;
+        assert_eq!(
+            netem_options(&impaired),
+            "delay 100ms loss 0% reorder 25% 50% gap 5 duplicate 1.5% corrupt 0.1%"
+        );
+    }
+
+    #[test]
+    fn netem_takes_the_queue_limit_and_ecn() {
+        let mut queued = conditions();
+        queued.max_bandwidth = Bandwidth::from_bps(1_000_000);
+        queued.queue = Some(QueueSize::Slots(50));
+        queued.ecn = true;
+        assert_eq!(
+            netem_options(&queued),
+            "delay 100ms loss 0% limit 50 ecn rate 1000000bit"
+        );
+    }
+
+    /// Configures pipe 1 and queue 2 feeding it, and returns the commands
+    fn dnctl_lines(conditions: &LinkConditions, mask: &FlowMask) -> Vec<String> {
+        let fake = Arc::new(FakeRunner::new());
+        let dnctl = DnctlCommands::new(fake.clone());
+        dnctl.configure_pipe(1, conditions, mask).unwrap();
+        dnctl.configure_queue(2, 1, 30, conditions, mask).unwrap();
+        fake.command_lines()
+    }
+
+    #[test]
+    fn dnctl_takes_the_queue_size_and_red() {
+        let mut queued = conditions();
+        queued.queue = Some(QueueSize::Slots(50));
+        assert_eq!(
+            dnctl_lines(&queued, &FlowMask::default()),
+            [
+                "dnctl pipe 1 config bw 0bit/s delay 100ms plr 0 queue 50",
+                "dnctl queue 2 config pipe 1 weight 30 queue 50",
+            ]
+        );
+
+        queued.queue = Some(QueueSize::Bytes(64_000));
+        queued.red = Some(Red::new(0.002, 10, 30, 0.1, false).unwrap());
+        assert_eq!(
+            dnctl_lines(&queued, &FlowMask::default()),
+            [
+                "dnctl pipe 1 config bw 0bit/s delay 100ms plr 0 queue 64000bytes \
+                 red 0.002/10/30/0.1",
+                "dnctl queue 2 config pipe 1 weight 30 queue 64000bytes red 0.002/10/30/0.1",
+            ]
+        );
+
+        queued.red = Some(Red::new(0.002, 10, 30, 0.1, true).unwrap());
+        assert_eq!(
+            dnctl_lines(&queued, &FlowMask::default())[0],
+            "dnctl pipe 1 config bw 0bit/s delay 100ms plr 0 queue 64000bytes gred 0.002/10/30/0.1"
+        );
+    }
+
+    #[test]
+    fn dnctl_takes_the_flow_mask() {
+        let all = "dnctl pipe 1 config bw 0bit/s delay 100ms plr 0 mask all";
+        assert_eq!(dnctl_lines(&conditions(), &FlowMask::five_tuple())[0], all);
+
+        let clients = FlowMask {
+            src_address: true,
+            src_port: true,
+            ..FlowMask::default()
+        };
+        assert_eq!(
+            dnctl_lines(&conditions(), &clients),
+            [
+                "dnctl pipe 1 config bw 0bit/s delay 100ms plr 0 \
+                 mask src-ip 0xffffffff src-ip6 /128 src-port 0xffff",
+                "dnctl queue 2 config pipe 1 weight 30 \
+                 mask src-ip 0xffffffff src-ip6 /128 src-port 0xffff",
+            ]
+        );
+
+        // Reversed for the inbound pipe, where the clients are the destination
+        let servers = FlowMask {
+            protocol: true,
+            ..clients.reversed()
+        };
+        assert_eq!(
+            dnctl_lines(&conditions(), &servers)[0],
+            "dnctl pipe 1 config bw 0bit/s delay 100ms plr 0 \
+             mask dst-ip 0xffffffff dst-ip6 /128 dst-port 0xffff proto 0xff"
+        );
+    }
 }
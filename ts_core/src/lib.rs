@@ -2,24 +2,211 @@ use chrono::prelude::*;
 use chrono::DateTime;
 use serde::{Deserialize, Serialize};
 use std::{
+    fmt,
     fs::{File, OpenOptions},
     io::Write,
+    net::IpAddr,
+    ops::{Deref, DerefMut},
+    str::FromStr,
 };
 use thiserror::Error;
-use tracing::{error, info};
+use tracing::error;
 
-mod commands;
-use commands::{DnctlCommands, PfctlCommands};
+mod backend;
+pub use backend::{
+    default_backend, dry_run_backend, PfBackend, ShaperBackend, TcBackend, TcpProxyBackend,
+    UdpProxyBackend,
+};
 
+mod commands;
+mod pf_rule;
 mod rules;
-use rules::RuleGenerator;
 
+mod status;
+pub use status::{DummynetStatus, FlowStatus, PipeStatus, QueueStatus};
+
+mod units;
+pub use units::{Bandwidth, Delay};
+
+mod runner;
+pub use runner::{
+    CommandOutput, CommandRunner, DryRunRunner, FakeRunner, Invocation, SystemRunner,
+};
+
+/// Protocol of the shaped traffic, carried over IP
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
-#[serde(rename_all = "lowercase")]
+#[serde(try_from = "ProtocolSpec")]
 pub enum Protocol {
     Tcp,
     Udp,
+    /// TCP and UDP
     Both,
+    /// ICMP, over IPv4 only
+    Icmp,
+    /// ICMPv6, over IPv6 only
+    Icmp6,
+    /// Every protocol
+    Any,
+    /// An IP protocol number, like 47 for GRE or 50 for ESP
+    Number(u8),
+}
+
+/// Database of the IP protocol names and their numbers
+const PROTOCOLS_PATH: &str = "/etc/protocols";
+
+impl Protocol {
+    /// Checks if the packets of the protocol carry ports
+    pub fn has_ports(self) -> bool {
+        matches!(
+            self,
+            Protocol::Tcp | Protocol::Udp | Protocol::Both | Protocol::Number(6 | 17)
+        )
+    }
+
+    /// Returns the address families the protocol is carried over
+    pub fn family(self) -> AddressFamily {
+        match self {
+            Protocol::Icmp => AddressFamily::Inet,
+            Protocol::Icmp6 => AddressFamily::Inet6,
+            _ => AddressFamily::Both,
+        }
+    }
+
+    /// Returns the IP protocol numbers matched, none for any protocol
+    pub(crate) fn numbers(self) -> Vec<u8> {
+        match self {
+            Protocol::Tcp => vec![6],
+            Protocol::Udp => vec![17],
+            Protocol::Both => vec![6, 17],
+            Protocol::Icmp => vec![1],
+            Protocol::Icmp6 => vec![58],
+            Protocol::Any => Vec::new(),
+            Protocol::Number(number) => vec![number],
+        }
+    }
+
+    /// Looks up the number of a protocol name or alias in /etc/protocols
+    pub fn named(name: &str) -> Result<Self, TrafficShapingError> {
+        let unknown = || TrafficShapingError::InvalidProtocol(name.to_string());
+        let protocols = std::fs::read_to_string(PROTOCOLS_PATH).map_err(|_| unknown())?;
+
+        // Lines look like `gre  47  GRE  # Generic Routing Encapsulation`
+        protocols
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or_default())
+            .find_map(|line| {
+                let mut fields = line.split_whitespace();
+                let protocol = fields.next()?;
+                let number = fields.next()?;
+                let mut aliases = fields;
+                (protocol == name || aliases.any(|alias| alias == name))
+                    .then(|| number.parse().ok())?
+            })
+            .map(Protocol::Number)
+            .ok_or_else(unknown)
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Udp => write!(f, "udp"),
+            Protocol::Both => write!(f, "both"),
+            Protocol::Icmp => write!(f, "icmp"),
+            Protocol::Icmp6 => write!(f, "icmp6"),
+            Protocol::Any => write!(f, "any"),
+            Protocol::Number(number) => write!(f, "{}", number),
+        }
+    }
+}
+
+impl FromStr for Protocol {
+    type Err = TrafficShapingError;
+
+    /// Parses `tcp`, `udp`, `both`, `icmp`, `icmp6`, `any`, a protocol
+    /// number, or a protocol name from /etc/protocols such as `gre`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        match s.to_lowercase().as_str() {
+            "tcp" => Ok(Protocol::Tcp),
+            "udp" => Ok(Protocol::Udp),
+            "both" => Ok(Protocol::Both),
+            "icmp" => Ok(Protocol::Icmp),
+            "icmp6" | "icmpv6" | "ipv6-icmp" => Ok(Protocol::Icmp6),
+            "any" | "all" => Ok(Protocol::Any),
+            name => match name.parse() {
+                Ok(number) => Ok(Protocol::Number(number)),
+                Err(_) => Protocol::named(name),
+            },
+        }
+    }
+}
+
+/// A protocol as written in a manifest, a number or a string parsed by
+/// `from_str`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ProtocolSpec {
+    Number(u8),
+    Name(String),
+}
+
+impl TryFrom<ProtocolSpec> for Protocol {
+    type Error = TrafficShapingError;
+
+    fn try_from(spec: ProtocolSpec) -> Result<Self, Self::Error> {
+        match spec {
+            ProtocolSpec::Number(number) => Ok(Protocol::Number(number)),
+            ProtocolSpec::Name(s) => s.parse(),
+        }
+    }
+}
+
+/// IP versions of the shaped traffic
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum AddressFamily {
+    /// IPv4 only
+    Inet,
+    /// IPv6 only
+    Inet6,
+    /// IPv4 and IPv6
+    #[default]
+    Both,
+}
+
+impl AddressFamily {
+    /// Checks if the family includes IPv4, or IPv6 if `ipv4` is false
+    pub fn includes(self, ipv4: bool) -> bool {
+        match self {
+            AddressFamily::Inet => ipv4,
+            AddressFamily::Inet6 => !ipv4,
+            AddressFamily::Both => true,
+        }
+    }
+}
+
+impl FromStr for AddressFamily {
+    type Err = TrafficShapingError;
+
+    /// Parses `inet` or `ipv4`, `inet6` or `ipv6`, and `both`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "inet" | "ipv4" => Ok(AddressFamily::Inet),
+            "inet6" | "ipv6" => Ok(AddressFamily::Inet6),
+            "both" => Ok(AddressFamily::Both),
+            _ => Err(TrafficShapingError::InvalidAddressFamily(s.to_string())),
+        }
+    }
+}
+
+impl TryFrom<String> for AddressFamily {
+    type Error = TrafficShapingError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
@@ -30,44 +217,904 @@ pub enum Output {
     File { path: String },
 }
 
-#[derive(Debug, Clone)]
-pub struct TrafficConfig {
+/// Direction of the shaped traffic, as seen from the shaped host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Traffic received by the host, the downlink
+    Inbound,
+    /// Traffic sent by the host, the uplink
+    Outbound,
+}
+
+/// Conditions applied to one direction of the traffic
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct LinkConditions {
     /// Packet loss percentage (0.0 to 100.0)
     pub packet_loss: f32,
-    /// Latency in milliseconds
-    pub latency: u32,
-    /// Maximum bandwidth in bits per second
-    pub max_bandwidth: u64,
-    /// Target protocol (TCP, UDP, or both)
+    /// Latency added to every packet
+    pub latency: Delay,
+    /// Maximum bandwidth, unlimited if 0
+    pub max_bandwidth: Bandwidth,
+    /// Variation of the latency, constant if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jitter: Option<Jitter>,
+    /// Bursty loss replacing the independent `packet_loss` if set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub burst_loss: Option<GilbertElliott>,
+    /// Packets sent ahead of the ones delayed before them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reorder: Option<Reorder>,
+    /// Duplicated packet percentage (0.0 to 100.0)
+    pub duplicate: f32,
+    /// Percentage of packets with a flipped bit (0.0 to 100.0)
+    pub corrupt: f32,
+    /// Capacity of the queue in front of the bandwidth limit, the default
+    /// of the backend if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue: Option<QueueSize>,
+    /// Drops packets before the queue is full instead of only once it is
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub red: Option<Red>,
+    /// Marks packets with ECN congestion experienced instead of dropping
+    /// them, for the transports that negotiated it
+    pub ecn: bool,
+}
+
+/// Capacity of a queue, in packets or in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueSize {
+    Slots(u32),
+    Bytes(u64),
+}
+
+/// Random early detection. Packets are dropped with a probability growing
+/// with the average length of the queue, measured in the unit of its size.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Red {
+    /// Weight (0.0 to 1.0) of the current length in the average length
+    pub weight: f32,
+    /// Average length below which no packet is dropped
+    pub min_threshold: u32,
+    /// Average length at which the drop probability reaches its maximum
+    pub max_threshold: u32,
+    /// Drop probability (0.0 to 1.0) at the maximum threshold
+    pub max_probability: f32,
+    /// Gentle RED: past the maximum threshold, the probability rises to 1
+    /// at twice the threshold instead of jumping to 1
+    #[serde(default)]
+    pub gentle: bool,
+}
+
+impl Red {
+    /// Creates a new Red with validation
+    pub fn new(
+        weight: f32,
+        min_threshold: u32,
+        max_threshold: u32,
+        max_probability: f32,
+        gentle: bool,
+    ) -> Result<Self, TrafficShapingError> {
+        if !(0.0..=1.0).contains(&weight) || !(0.0..=1.0).contains(&max_probability) {
+            return Err(TrafficShapingError::InvalidRed(
+                "weight and maximum probability must be between 0 and 1".to_string(),
+            ));
+        }
+        if min_threshold >= max_threshold {
+            return Err(TrafficShapingError::InvalidRed(format!(
+                "minimum threshold ({}) must be less than the maximum threshold ({})",
+                min_threshold, max_threshold
+            )));
+        }
+
+        Ok(Self {
+            weight,
+            min_threshold,
+            max_threshold,
+            max_probability,
+            gentle,
+        })
+    }
+}
+
+/// Reordering of packets, by sending some of them without the latency so
+/// they overtake the packets sent before them
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Reorder {
+    /// Percentage (0.0 to 100.0) of the candidate packets sent early
+    pub probability: f32,
+    /// Correlation percentage (0.0 to 100.0) with the previous decision
+    #[serde(default)]
+    pub correlation: f32,
+    /// Only every `gap`th packet is a candidate, 1 makes every packet one
+    #[serde(default = "Reorder::default_gap")]
+    pub gap: u32,
+}
+
+impl Reorder {
+    /// Creates a new Reorder with validation
+    pub fn new(probability: f32, correlation: f32, gap: u32) -> Result<Self, TrafficShapingError> {
+        check_percentage("reorder", probability)?;
+        check_percentage("correlation", correlation)?;
+
+        Ok(Self {
+            probability,
+            correlation,
+            gap: gap.max(1),
+        })
+    }
+
+    fn default_gap() -> u32 {
+        1
+    }
+}
+
+fn check_percentage(name: &'static str, value: f32) -> Result<(), TrafficShapingError> {
+    if !(0.0..=100.0).contains(&value) {
+        return Err(TrafficShapingError::InvalidPercentage { name, value });
+    }
+    Ok(())
+}
+
+/// Two-state Gilbert-Elliott loss model. Packets are lost rarely in the
+/// good state and often in the bad one, so losses come in bursts whose
+/// length depends on how long the link stays in the bad state.
+///
+/// All values are percentages (0.0 to 100.0), transitions are evaluated
+/// once per packet.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GilbertElliott {
+    /// Probability of moving from the good to the bad state
+    pub to_bad: f32,
+    /// Probability of moving from the bad to the good state
+    pub to_good: f32,
+    /// Loss probability in the bad state
+    #[serde(default = "GilbertElliott::default_bad_loss")]
+    pub bad_loss: f32,
+    /// Loss probability in the good state
+    #[serde(default)]
+    pub good_loss: f32,
+}
+
+impl GilbertElliott {
+    /// Creates a new GilbertElliott model with validation
+    pub fn new(
+        to_bad: f32,
+        to_good: f32,
+        bad_loss: f32,
+        good_loss: f32,
+    ) -> Result<Self, TrafficShapingError> {
+        check_percentage("good to bad transition", to_bad)?;
+        check_percentage("bad to good transition", to_good)?;
+        check_percentage("bad state loss", bad_loss)?;
+        check_percentage("good state loss", good_loss)?;
+
+        Ok(Self {
+            to_bad,
+            to_good,
+            bad_loss,
+            good_loss,
+        })
+    }
+
+    fn default_bad_loss() -> f32 {
+        100.0
+    }
+}
+
+/// Random variation of the latency of each packet around its mean
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Jitter {
+    /// The half width of the range for a uniform distribution, the
+    /// standard deviation otherwise
+    pub deviation: Delay,
+    #[serde(default)]
+    pub distribution: DelayDistribution,
+    /// Correlation percentage (0.0 to 100.0) of the delay of a packet with
+    /// the one of the previous packet
+    #[serde(default)]
+    pub correlation: f32,
+}
+
+/// Shape of the distribution of delays around the latency
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DelayDistribution {
+    #[default]
+    Uniform,
+    Normal,
+    Pareto,
+}
+
+impl Jitter {
+    /// Creates a new Jitter with validation
+    pub fn new(
+        deviation: Delay,
+        distribution: DelayDistribution,
+        correlation: f32,
+    ) -> Result<Self, TrafficShapingError> {
+        check_percentage("correlation", correlation)?;
+
+        Ok(Self {
+            deviation,
+            distribution,
+            correlation,
+        })
+    }
+
+    /// Uniform jitter spreading the delay between `min` and `max`, with the
+    /// latency set to the middle of the range
+    pub fn range(min: Delay, max: Delay) -> (Delay, Self) {
+        let (min, max) = (min.min(max).as_duration(), min.max(max).as_duration());
+        let latency = min + (max - min) / 2;
+        (
+            latency.into(),
+            Self {
+                deviation: (max - latency).into(),
+                distribution: DelayDistribution::Uniform,
+                correlation: 0.0,
+            },
+        )
+    }
+}
+
+impl LinkConditions {
+    /// Creates new LinkConditions with validation
+    pub fn new(
+        packet_loss: f32,
+        latency: Delay,
+        max_bandwidth: Bandwidth,
+    ) -> Result<Self, TrafficShapingError> {
+        if !(0.0..=100.0).contains(&packet_loss) {
+            return Err(TrafficShapingError::InvalidPacketLoss(packet_loss));
+        }
+
+        Ok(Self {
+            packet_loss,
+            latency,
+            max_bandwidth,
+            ..Default::default()
+        })
+    }
+
+    /// Adds random variation to the latency
+    pub fn with_jitter(mut self, jitter: Jitter) -> Self {
+        self.jitter = Some(jitter);
+        self
+    }
+
+    /// Loses packets in bursts instead of independently
+    pub fn with_burst_loss(mut self, model: GilbertElliott) -> Self {
+        self.burst_loss = Some(model);
+        self
+    }
+
+    /// Sends some packets ahead of the ones sent before them
+    pub fn with_reorder(mut self, reorder: Reorder) -> Self {
+        self.reorder = Some(reorder);
+        self
+    }
+
+    /// Duplicates the given percentage of packets
+    pub fn with_duplicate(mut self, duplicate: f32) -> Result<Self, TrafficShapingError> {
+        check_percentage("duplicate", duplicate)?;
+        self.duplicate = duplicate;
+        Ok(self)
+    }
+
+    /// Flips a random bit in the given percentage of packets
+    pub fn with_corrupt(mut self, corrupt: f32) -> Result<Self, TrafficShapingError> {
+        check_percentage("corrupt", corrupt)?;
+        self.corrupt = corrupt;
+        Ok(self)
+    }
+
+    /// Bounds the queue in front of the bandwidth limit
+    pub fn with_queue(mut self, queue: QueueSize) -> Self {
+        self.queue = Some(queue);
+        self
+    }
+
+    /// Drops packets early as the queue fills up
+    pub fn with_red(mut self, red: Red) -> Self {
+        self.red = Some(red);
+        self
+    }
+
+    /// Marks packets instead of dropping them
+    pub fn with_ecn(mut self, ecn: bool) -> Self {
+        self.ecn = ecn;
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TrafficConfig {
+    /// Conditions of the traffic received by the host
+    pub inbound: LinkConditions,
+    /// Conditions of the traffic sent by the host
+    pub outbound: LinkConditions,
+    /// Target protocol (TCP, UDP, both, ICMP, any or a protocol number)
     pub protocol: Protocol,
+    /// Address families to target, both by default
+    pub family: AddressFamily,
 
-    pub src_ports: Option<PortRange>,
-    pub dst_ports: Option<PortRange>,
+    /// Source ports and port ranges to target, any if empty
+    pub src_ports: Vec<PortRange>,
+    /// Destination ports and port ranges to target, any if empty
+    pub dst_ports: Vec<PortRange>,
+    /// Source hosts or networks to target, any if empty
+    pub src_addresses: Vec<IpNetwork>,
+    /// Destination hosts or networks to target, any if empty
+    pub dst_addresses: Vec<IpNetwork>,
+    /// Interfaces the shaping is bound to
+    pub interfaces: Interfaces,
+    /// Fields splitting the traffic into flows, each shaped on its own
+    pub flow_mask: FlowMask,
+    /// Classes sharing the conditions by weighted fair queueing, none if
+    /// all the traffic is queued together
+    pub classes: Vec<TrafficClass>,
     pub report_output: Output,
 }
 
+/// Name of the rule created from the configuration a `TrafficShaper` is
+/// built with
+pub const DEFAULT_RULE: &str = "default";
+
+/// A named set of conditions and the traffic they apply to. Each rule is
+/// shaped independently from the others.
+#[derive(Debug, Clone)]
+pub struct ShapingRule {
+    pub name: String,
+    pub config: TrafficConfig,
+}
+
+/// Weights accepted for a traffic class, the range of dummynet queues
+const WEIGHTS: std::ops::RangeInclusive<u32> = 1..=100;
+
+/// A class of the traffic of a rule, queued apart from the other classes
+/// in front of the shared conditions of the rule. When the link is
+/// congested, each class gets a share of the bandwidth proportional to its
+/// weight, like video and game traffic competing for one bottleneck.
 #[derive(Debug, Clone)]
+pub struct TrafficClass {
+    pub name: String,
+    /// Share of the bandwidth relative to the other classes (1 to 100)
+    pub weight: u32,
+    pub protocol: Protocol,
+    /// Source ports and port ranges of the class, any if empty
+    pub src_ports: Vec<PortRange>,
+    /// Destination ports and port ranges of the class, any if empty
+    pub dst_ports: Vec<PortRange>,
+    /// Source hosts or networks of the class, any if empty
+    pub src_addresses: Vec<IpNetwork>,
+    /// Destination hosts or networks of the class, any if empty
+    pub dst_addresses: Vec<IpNetwork>,
+}
+
+impl TrafficClass {
+    /// Creates a new TrafficClass with validation
+    pub fn new(
+        name: impl Into<String>,
+        weight: u32,
+        protocol: Protocol,
+        src_ports: Vec<PortRange>,
+        dst_ports: Vec<PortRange>,
+    ) -> Result<Self, TrafficShapingError> {
+        if !WEIGHTS.contains(&weight) {
+            return Err(TrafficShapingError::InvalidWeight(weight));
+        }
+
+        Ok(Self {
+            name: name.into(),
+            weight,
+            protocol,
+            src_ports,
+            dst_ports,
+            src_addresses: Vec::new(),
+            dst_addresses: Vec::new(),
+        })
+    }
+
+    /// Restricts the class to traffic from the given hosts or networks
+    pub fn with_src_addresses(mut self, addresses: Vec<IpNetwork>) -> Self {
+        self.src_addresses = addresses;
+        self
+    }
+
+    /// Restricts the class to traffic to the given hosts or networks
+    pub fn with_dst_addresses(mut self, addresses: Vec<IpNetwork>) -> Self {
+        self.dst_addresses = addresses;
+        self
+    }
+
+    /// Returns the config of a rule matching the traffic of the class only
+    pub(crate) fn filter(&self, config: &TrafficConfig) -> TrafficConfig {
+        TrafficConfig {
+            protocol: self.protocol,
+            src_ports: self.src_ports.clone(),
+            dst_ports: self.dst_ports.clone(),
+            src_addresses: self.src_addresses.clone(),
+            dst_addresses: self.dst_addresses.clone(),
+            classes: Vec::new(),
+            ..config.clone()
+        }
+    }
+}
+
+/// A port, or a range of ports, `start` and `end` included
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "PortSpec")]
 pub struct PortRange {
     pub start: u16,
     pub end: u16,
 }
 
-#[derive(Debug, Clone)]
+/// Database of the well-known service names and their ports
+const SERVICES_PATH: &str = "/etc/services";
+
+impl PortRange {
+    /// Creates a range, checking it is not empty
+    pub fn new(start: u16, end: u16) -> Result<Self, TrafficShapingError> {
+        if start > end {
+            return Err(TrafficShapingError::InvalidPortRange { start, end });
+        }
+        Ok(Self { start, end })
+    }
+
+    /// Creates a range holding a single port
+    pub fn single(port: u16) -> Self {
+        Self {
+            start: port,
+            end: port,
+        }
+    }
+
+    /// Looks up the port of a service name or alias in /etc/services, for
+    /// any protocol
+    pub fn service(name: &str) -> Result<Self, TrafficShapingError> {
+        let unknown = || TrafficShapingError::InvalidPort(name.to_string());
+        let services = std::fs::read_to_string(SERVICES_PATH).map_err(|_| unknown())?;
+
+        // Lines look like `https  443/tcp  # http protocol over TLS/SSL`
+        services
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or_default())
+            .find_map(|line| {
+                let mut fields = line.split_whitespace();
+                let service = fields.next()?;
+                let (port, _) = fields.next()?.split_once('/')?;
+                let mut aliases = fields;
+                (service == name || aliases.any(|alias| alias == name))
+                    .then(|| port.parse().ok())?
+            })
+            .map(Self::single)
+            .ok_or_else(unknown)
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = TrafficShapingError;
+
+    /// Parses a port, a range written `start-end` or `start:end`, or a
+    /// service name from /etc/services
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(port) = s.parse() {
+            return Ok(Self::single(port));
+        }
+        // Service names may contain dashes too, like `ms-sql-s`
+        if let Some((start, end)) = s.split_once(['-', ':']) {
+            if let (Ok(start), Ok(end)) = (start.parse(), end.parse()) {
+                return Self::new(start, end);
+            }
+        }
+        Self::service(s)
+    }
+}
+
+/// A port as written in a manifest, a number or a string parsed by
+/// `from_str`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PortSpec {
+    Number(u16),
+    Name(String),
+}
+
+impl TryFrom<PortSpec> for PortRange {
+    type Error = TrafficShapingError;
+
+    fn try_from(spec: PortSpec) -> Result<Self, Self::Error> {
+        match spec {
+            PortSpec::Number(port) => Ok(Self::single(port)),
+            PortSpec::Name(s) => s.parse(),
+        }
+    }
+}
+
+/// A host or a network in CIDR notation, IPv4 or IPv6
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNetwork {
+    /// Creates a network, checking the prefix length fits the address family
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self, TrafficShapingError> {
+        if prefix > Self::max_prefix(&addr) {
+            return Err(TrafficShapingError::InvalidAddress(format!(
+                "{}/{}",
+                addr, prefix
+            )));
+        }
+        Ok(Self { addr, prefix })
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    pub fn is_ipv4(&self) -> bool {
+        self.addr.is_ipv4()
+    }
+
+    /// Checks if the network is a single host
+    pub fn is_host(&self) -> bool {
+        self.prefix == Self::max_prefix(&self.addr)
+    }
+
+    fn max_prefix(addr: &IpAddr) -> u8 {
+        if addr.is_ipv4() {
+            32
+        } else {
+            128
+        }
+    }
+}
+
+impl From<IpAddr> for IpNetwork {
+    fn from(addr: IpAddr) -> Self {
+        Self {
+            addr,
+            prefix: Self::max_prefix(&addr),
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = TrafficShapingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || TrafficShapingError::InvalidAddress(s.to_string());
+        match s.split_once('/') {
+            Some((addr, prefix)) => Self::new(
+                addr.parse().map_err(|_| invalid())?,
+                prefix.parse().map_err(|_| invalid())?,
+            ),
+            None => Ok(Self::from(s.parse::<IpAddr>().map_err(|_| invalid())?)),
+        }
+    }
+}
+
+impl TryFrom<String> for IpNetwork {
+    type Error = TrafficShapingError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_host() {
+            write!(f, "{}", self.addr)
+        } else {
+            write!(f, "{}/{}", self.addr, self.prefix)
+        }
+    }
+}
+
+/// Network interfaces a rule is bound to.
+///
+/// Loopback traffic leaves and enters the host on the same interface, so it
+/// is only shaped on the way out, with the outbound conditions, instead of
+/// being delayed twice.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "InterfacesSpec")]
+pub enum Interfaces {
+    /// Every interface
+    #[default]
+    All,
+    /// Only the loopback interface
+    Loopback,
+    /// Every interface except loopback
+    NonLoopback,
+    /// The named interfaces, e.g. `en0` or `utun3`
+    Named(Vec<String>),
+}
+
+impl Interfaces {
+    /// Checks if a name is the one of a loopback interface, `lo0` on macOS
+    /// or `lo` on Linux
+    pub fn is_loopback(name: &str) -> bool {
+        name.strip_prefix("lo")
+            .is_some_and(|unit| unit.chars().all(|c| c.is_ascii_digit()))
+    }
+
+    /// Checks if the interface with the given name is shaped
+    pub fn includes(&self, name: &str) -> bool {
+        match self {
+            Interfaces::All => true,
+            Interfaces::Loopback => Self::is_loopback(name),
+            Interfaces::NonLoopback => !Self::is_loopback(name),
+            Interfaces::Named(names) => names.iter().any(|n| n == name),
+        }
+    }
+}
+
+impl FromStr for Interfaces {
+    type Err = TrafficShapingError;
+
+    /// Parses `all`, `loopback`, `non-loopback` or a comma separated list of
+    /// interface names
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(Interfaces::All),
+            "loopback" => Ok(Interfaces::Loopback),
+            "non-loopback" => Ok(Interfaces::NonLoopback),
+            _ => {
+                let names: Vec<String> = s.split(',').map(|name| name.trim().to_string()).collect();
+                if names.iter().any(|name| name.is_empty()) {
+                    return Err(TrafficShapingError::InvalidInterface(s.to_string()));
+                }
+                Ok(Interfaces::Named(names))
+            }
+        }
+    }
+}
+
+/// Interfaces as written in a manifest, either a keyword or name, or a
+/// list of names
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum InterfacesSpec {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl TryFrom<InterfacesSpec> for Interfaces {
+    type Error = TrafficShapingError;
+
+    fn try_from(spec: InterfacesSpec) -> Result<Self, Self::Error> {
+        match spec {
+            InterfacesSpec::One(s) => s.parse(),
+            InterfacesSpec::Many(names) if names.is_empty() => {
+                Err(TrafficShapingError::InvalidInterface(String::new()))
+            }
+            InterfacesSpec::Many(names) => Ok(Interfaces::Named(names)),
+        }
+    }
+}
+
+/// Fields of a packet telling its flow apart from the others of a rule, in
+/// the terms of the outbound traffic. Every flow gets its own bandwidth,
+/// queue and loss, like the dynamic pipes of a dummynet mask: with a mask
+/// on the source port, ten clients capped at 1 Mbit/s get 1 Mbit/s each
+/// instead of sharing it. An empty mask, the default, shares them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "FlowMaskSpec")]
+pub struct FlowMask {
+    pub src_address: bool,
+    pub dst_address: bool,
+    pub src_port: bool,
+    pub dst_port: bool,
+    pub protocol: bool,
+}
+
+impl FlowMask {
+    /// Tells flows apart by their full 5-tuple
+    pub fn five_tuple() -> Self {
+        Self {
+            src_address: true,
+            dst_address: true,
+            src_port: true,
+            dst_port: true,
+            protocol: true,
+        }
+    }
+
+    /// Checks if every flow is shaped together
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Returns the mask telling the same flows apart in the inbound traffic,
+    /// where the source and destination are swapped
+    pub fn reversed(&self) -> Self {
+        Self {
+            src_address: self.dst_address,
+            dst_address: self.src_address,
+            src_port: self.dst_port,
+            dst_port: self.src_port,
+            protocol: self.protocol,
+        }
+    }
+}
+
+impl FromStr for FlowMask {
+    type Err = TrafficShapingError;
+
+    /// Parses `none`, `all` or a comma separated list of `src-address`,
+    /// `dst-address`, `src-port`, `dst-port` and `proto`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::default()),
+            "all" => Ok(Self::five_tuple()),
+            _ => s.split(',').try_fold(Self::default(), |mut mask, field| {
+                match field.trim() {
+                    "src-address" => mask.src_address = true,
+                    "dst-address" => mask.dst_address = true,
+                    "src-port" => mask.src_port = true,
+                    "dst-port" => mask.dst_port = true,
+                    "proto" => mask.protocol = true,
+                    _ => return Err(TrafficShapingError::InvalidFlowMask(s.to_string())),
+                }
+                Ok(mask)
+            }),
+        }
+    }
+}
+
+impl fmt::Display for FlowMask {
+    /// Formats the mask the way `from_str` parses it
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "none");
+        }
+        if *self == Self::five_tuple() {
+            return write!(f, "all");
+        }
+        let fields = [
+            ("src-address", self.src_address),
+            ("dst-address", self.dst_address),
+            ("src-port", self.src_port),
+            ("dst-port", self.dst_port),
+            ("proto", self.protocol),
+        ];
+        let names: Vec<&str> = fields
+            .iter()
+            .filter(|(_, set)| *set)
+            .map(|(name, _)| *name)
+            .collect();
+        write!(f, "{}", names.join(","))
+    }
+}
+
+impl Serialize for FlowMask {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Flow mask as written in a manifest, either a keyword or field, or a
+/// list of fields
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FlowMaskSpec {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl TryFrom<FlowMaskSpec> for FlowMask {
+    type Error = TrafficShapingError;
+
+    fn try_from(spec: FlowMaskSpec) -> Result<Self, Self::Error> {
+        match spec {
+            FlowMaskSpec::One(s) => s.parse(),
+            FlowMaskSpec::Many(fields) if fields.is_empty() => Ok(Self::default()),
+            FlowMaskSpec::Many(fields) => fields.join(",").parse(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ApplyConfig {
-    /// Packet loss percentage (0.0 to 100.0)
-    pub packet_loss: f32,
-    /// Latency in milliseconds
-    pub latency: u32,
-    /// Maximum bandwidth in bits per second
-    pub max_bandwidth: u64,
+    /// Conditions of the traffic received by the host
+    pub inbound: LinkConditions,
+    /// Conditions of the traffic sent by the host
+    pub outbound: LinkConditions,
+}
+
+impl ApplyConfig {
+    /// Applies the same conditions to both directions
+    pub fn symmetric(packet_loss: f32, latency: Delay, max_bandwidth: Bandwidth) -> Self {
+        let conditions = LinkConditions {
+            packet_loss,
+            latency,
+            max_bandwidth,
+            ..Default::default()
+        };
+        Self {
+            inbound: conditions.clone(),
+            outbound: conditions,
+        }
+    }
+
+    /// Returns the conditions of the given direction
+    pub fn conditions(&self, direction: Direction) -> &LinkConditions {
+        match direction {
+            Direction::Inbound => &self.inbound,
+            Direction::Outbound => &self.outbound,
+        }
+    }
+}
+
+impl From<&TrafficConfig> for ApplyConfig {
+    fn from(config: &TrafficConfig) -> Self {
+        ApplyConfig {
+            inbound: config.inbound.clone(),
+            outbound: config.outbound.clone(),
+        }
+    }
 }
 
 #[derive(Error, Debug)]
 pub enum TrafficShapingError {
     #[error("Invalid packet loss percentage: {0}. Must be between 0 and 100")]
     InvalidPacketLoss(f32),
+    #[error("Invalid {name} percentage: {value}. Must be between 0 and 100")]
+    InvalidPercentage { name: &'static str, value: f32 },
+    #[error("Invalid bandwidth: {0:?}. Must be bits per second or a value with a unit, like 10Mbit or 1.5MB/s")]
+    InvalidBandwidth(String),
+    #[error(
+        "Invalid duration: {0:?}. Must be milliseconds or a value with a unit, like 250ms or 1.2s"
+    )]
+    InvalidDuration(String),
+    #[error("Invalid RED parameters: {0}")]
+    InvalidRed(String),
     #[error("Invalid port range: start ({start}) must be less than or equal to end ({end})")]
     InvalidPortRange { start: u16, end: u16 },
+    #[error("Invalid port: {0:?}. Must be a port, a range start-end or a service name")]
+    InvalidPort(String),
+    #[error("Invalid address: {0}. Must be an IP address or a CIDR block")]
+    InvalidAddress(String),
+    #[error("Invalid protocol: {0:?}")]
+    InvalidProtocol(String),
+    #[error("Invalid address family: {0:?}. Must be inet, inet6 or both")]
+    InvalidAddressFamily(String),
+    #[error("Ports only apply to TCP and UDP, not to protocol {0}")]
+    PortsWithoutTransport(Protocol),
+    #[error("The family, protocol and addresses of the traffic share no address family")]
+    MixedAddressFamilies,
+    #[error("Invalid interface: {0:?}")]
+    InvalidInterface(String),
+    #[error("Invalid weight: {0}. Must be between 1 and 100")]
+    InvalidWeight(u32),
+    #[error("Invalid flow mask: {0:?}")]
+    InvalidFlowMask(String),
+    #[error("Invalid pf rule: {0:?}")]
+    InvalidPfRule(String),
+    #[error("Unknown interface: {0}")]
+    UnknownInterface(String),
+    #[error("Unknown rule: {0}")]
+    UnknownRule(String),
+    #[error("Duplicate rule: {0}")]
+    DuplicateRule(String),
+    #[error("Traffic shaping already started by process {0}, stop it first")]
+    SessionActive(u32),
+    #[error(
+        "Unreadable session, moved to {path}: {reason}. The changes it recorded were not undone"
+    )]
+    UnreadableSession { path: String, reason: String },
+    #[error("Unsupported by the backend: {0}")]
+    Unsupported(String),
     #[error("Command execution failed: {0}")]
     CommandError(String),
     #[error("System error: {0}")]
@@ -75,78 +1122,171 @@ pub enum TrafficShapingError {
 }
 
 impl TrafficConfig {
-    /// Creates a new TrafficConfig with validation
+    /// Creates a new TrafficConfig with validation, applying the same
+    /// conditions to both directions
     pub fn new(
         packet_loss: f32,
-        latency: u32,
-        max_bandwidth: u64,
+        latency: Delay,
+        max_bandwidth: Bandwidth,
         protocol: Protocol,
         src_ports: Option<PortRange>,
         dst_ports: Option<PortRange>,
         output: Output,
     ) -> Result<Self, TrafficShapingError> {
-        // Validate packet loss
-        if !(0.0..=100.0).contains(&packet_loss) {
-            return Err(TrafficShapingError::InvalidPacketLoss(packet_loss));
-        }
+        let conditions = LinkConditions::new(packet_loss, latency, max_bandwidth)?;
 
         Ok(Self {
-            packet_loss,
-            latency,
-            max_bandwidth,
+            inbound: conditions.clone(),
+            outbound: conditions,
             protocol,
-            src_ports,
-            dst_ports,
+            family: AddressFamily::Both,
+            src_ports: src_ports.into_iter().collect(),
+            dst_ports: dst_ports.into_iter().collect(),
+            src_addresses: Vec::new(),
+            dst_addresses: Vec::new(),
+            interfaces: Interfaces::All,
+            flow_mask: FlowMask::default(),
+            classes: Vec::new(),
             report_output: output,
         })
     }
-}
 
-const DEFAULT_PIPE_NUMBER: u32 = 1;
+    /// Restricts shaping to IPv4 or IPv6 traffic
+    pub fn with_family(mut self, family: AddressFamily) -> Self {
+        self.family = family;
+        self
+    }
+
+    /// Restricts shaping to traffic from the given ports and port ranges
+    pub fn with_src_ports(mut self, ports: Vec<PortRange>) -> Self {
+        self.src_ports = ports;
+        self
+    }
+
+    /// Restricts shaping to traffic to the given ports and port ranges
+    pub fn with_dst_ports(mut self, ports: Vec<PortRange>) -> Self {
+        self.dst_ports = ports;
+        self
+    }
+
+    /// Restricts shaping to traffic from the given hosts or networks
+    pub fn with_src_addresses(mut self, addresses: Vec<IpNetwork>) -> Self {
+        self.src_addresses = addresses;
+        self
+    }
+
+    /// Restricts shaping to traffic to the given hosts or networks
+    pub fn with_dst_addresses(mut self, addresses: Vec<IpNetwork>) -> Self {
+        self.dst_addresses = addresses;
+        self
+    }
+
+    /// Adds the same random variation to the latency of both directions
+    pub fn with_jitter(mut self, jitter: Jitter) -> Self {
+        self.inbound.jitter = Some(jitter.clone());
+        self.outbound.jitter = Some(jitter);
+        self
+    }
+
+    /// Loses packets of both directions in bursts instead of independently
+    pub fn with_burst_loss(mut self, model: GilbertElliott) -> Self {
+        self.inbound.burst_loss = Some(model.clone());
+        self.outbound.burst_loss = Some(model);
+        self
+    }
+
+    /// Binds shaping to the given interfaces
+    pub fn with_interfaces(mut self, interfaces: Interfaces) -> Self {
+        self.interfaces = interfaces;
+        self
+    }
+
+    /// Gives every flow told apart by the mask its own conditions, instead of
+    /// sharing them with the other flows of the rule
+    pub fn with_flow_mask(mut self, flow_mask: FlowMask) -> Self {
+        self.flow_mask = flow_mask;
+        self
+    }
+
+    /// Splits the traffic into classes sharing the conditions of the rule
+    /// by their weights. The protocol, ports and addresses of the classes
+    /// replace the ones of the rule.
+    pub fn with_classes(mut self, classes: Vec<TrafficClass>) -> Self {
+        self.classes = classes;
+        self
+    }
+
+    /// Replaces the conditions of the traffic received by the host
+    pub fn with_inbound(mut self, inbound: LinkConditions) -> Self {
+        self.inbound = inbound;
+        self
+    }
+
+    /// Replaces the conditions of the traffic sent by the host
+    pub fn with_outbound(mut self, outbound: LinkConditions) -> Self {
+        self.outbound = outbound;
+        self
+    }
+}
 
 /// Main traffic shaper struct that handles the configuration and execution
 pub struct TrafficShaper {
-    config: TrafficConfig,
+    rules: Vec<ShapingRule>,
+    report_output: Output,
+    backend: Box<dyn ShaperBackend>,
     file_handle: Option<File>,
 }
 
 impl TrafficShaper {
+    /// Creates a shaper using the backend native to the current platform
     pub fn new(config: TrafficConfig) -> Self {
+        Self::with_backend(config, default_backend())
+    }
+
+    /// Creates a shaper printing the pf rules and the commands the backend
+    /// native to the platform would run, without running them. Needs no
+    /// privileges.
+    pub fn dry_run(config: TrafficConfig) -> Self {
+        Self::with_backend(config, dry_run_backend())
+    }
+
+    /// Creates a shaper driving the given backend
+    pub fn with_backend(config: TrafficConfig, backend: Box<dyn ShaperBackend>) -> Self {
         Self {
-            config,
+            report_output: config.report_output.clone(),
+            rules: vec![ShapingRule {
+                name: DEFAULT_RULE.to_string(),
+                config,
+            }],
+            backend,
             file_handle: None,
         }
     }
 
-    /// Applies the traffic shaping rules
-    pub fn enable(&mut self) -> Result<(), TrafficShapingError> {
-        // Step 1: Enable PF if not already enabled
-        PfctlCommands::enable()?;
-        info!("pfctl enabled");
+    /// Adds a rule shaped alongside the default one. Must be called before
+    /// `enable`, and the report output of the rule's config is ignored.
+    pub fn add_rule(
+        &mut self,
+        name: &str,
+        config: TrafficConfig,
+    ) -> Result<(), TrafficShapingError> {
+        if self.rules.iter().any(|rule| rule.name == name) {
+            return Err(TrafficShapingError::DuplicateRule(name.to_string()));
+        }
 
-        // Step 2: Configure dummynet pipe with the specified configuration
-        // The pipe will be created if it doesn't exist, or updated if it does
-        DnctlCommands::configure_pipe(
-            DEFAULT_PIPE_NUMBER,
-            Some(self.config.max_bandwidth),
-            Some(self.config.latency),
-            Some(self.config.packet_loss / 100.0), // Convert percentage to ratio
-        )?;
-        info!("configured pipe");
+        self.rules.push(ShapingRule {
+            name: name.to_string(),
+            config,
+        });
+        Ok(())
+    }
 
-        // Step 3: Generate and load PF rules only if the pipe didn't exist
-        if !DnctlCommands::pipe_exists(DEFAULT_PIPE_NUMBER)? {
-            let anchor_name = String::from("traffic_shaper");
-            let anchor_rules = RuleGenerator::generate_anchor_rules(&anchor_name)?;
-            PfctlCommands::load_rules(&anchor_rules, Some(&anchor_name))?;
-            info!("loaded anchor rules");
+    /// Applies the traffic shaping rules. The shaping is removed when the
+    /// returned guard is dropped, unless it is kept.
+    pub fn enable(&mut self) -> Result<ShapingGuard<'_>, TrafficShapingError> {
+        self.backend.enable(&self.rules)?;
 
-            let rules = RuleGenerator::generate_pf_rules(&self.config, DEFAULT_PIPE_NUMBER)?;
-            PfctlCommands::load_rules(&rules, None)?;
-            info!("loaded pf rules");
-        }
-
-        self.file_handle = match &self.config.report_output {
+        self.file_handle = match &self.report_output {
             Output::File { path } => OpenOptions::new()
                 .create(true)
                 .write(true)
@@ -160,28 +1300,39 @@ impl TrafficShaper {
             _ => None,
         };
 
-        Ok(())
+        Ok(ShapingGuard {
+            shaper: self,
+            armed: true,
+        })
     }
 
+    /// Applies new conditions to the default rule
     pub fn apply(&mut self, config: ApplyConfig) -> Result<(), TrafficShapingError> {
-        DnctlCommands::configure_pipe(
-            DEFAULT_PIPE_NUMBER,
-            Some(config.max_bandwidth),
-            Some(config.latency),
-            Some(config.packet_loss / 100.0),
-        )?;
-
-        if self.config.report_output == Output::None {
+        self.apply_rule(DEFAULT_RULE, config)
+    }
+
+    /// Applies new conditions to the named rule, leaving the others untouched
+    pub fn apply_rule(
+        &mut self,
+        name: &str,
+        config: ApplyConfig,
+    ) -> Result<(), TrafficShapingError> {
+        if !self.rules.iter().any(|rule| rule.name == name) {
+            return Err(TrafficShapingError::UnknownRule(name.to_string()));
+        }
+
+        self.backend.apply(name, &config)?;
+
+        if self.report_output == Output::None {
             return Ok(());
         }
 
-        let event_report =
-            EventReport::new(config.max_bandwidth, config.latency, config.packet_loss);
+        let event_report = EventReport::new(name, &config);
 
         match serde_json::to_string(&event_report) {
             Ok(mut v) => {
                 v.push('\n');
-                match &self.config.report_output {
+                match &self.report_output {
                     Output::Stdout => {
                         let _ = std::io::stdout().write_all(v.as_bytes());
                     }
@@ -199,36 +1350,167 @@ impl TrafficShaper {
         Ok(())
     }
 
+    /// Checks if the shaping is currently active
+    pub fn is_active(&self) -> Result<bool, TrafficShapingError> {
+        self.backend.is_active()
+    }
+
+    /// Checks if the backend needs root to modify the system
+    pub fn requires_privileges(&self) -> bool {
+        self.backend.requires_privileges()
+    }
+
+    /// Lists the dummynet pipes and queues with their settings and counters
+    pub fn dummynet_status(&self) -> Result<DummynetStatus, TrafficShapingError> {
+        self.backend.dummynet_status()
+    }
+
     /// Removes traffic shaping rules and restores original configuration
-    pub fn cleanup(&self) -> Result<(), TrafficShapingError> {
-        // Clean up dummynet pipes
-        DnctlCommands::flush_pipes()?;
+    pub fn cleanup(&mut self) -> Result<(), TrafficShapingError> {
+        self.backend.cleanup()
+    }
+}
 
-        // Restore original PF rules
-        PfctlCommands::restore_original_rules()?;
+/// Shaping enabled by `TrafficShaper::enable`, removed when the guard is
+/// dropped, including while unwinding from a panic. Conditions are applied
+/// through the guard, which derefs to the shaper.
+#[must_use = "the shaping is removed as soon as the guard is dropped"]
+pub struct ShapingGuard<'a> {
+    shaper: &'a mut TrafficShaper,
+    armed: bool,
+}
 
-        // Disable PF if no other references exist
-        PfctlCommands::disable()?;
+impl ShapingGuard<'_> {
+    /// Removes the shaping now, reporting the error dropping would only log
+    pub fn cleanup(mut self) -> Result<(), TrafficShapingError> {
+        self.armed = false;
+        self.shaper.cleanup()
+    }
 
-        Ok(())
+    /// Leaves the shaping in place once the guard is gone, to be removed by
+    /// a later `cleanup`, e.g. from `traffic-shaper stop`
+    pub fn keep(mut self) {
+        self.armed = false;
+    }
+}
+
+impl Deref for ShapingGuard<'_> {
+    type Target = TrafficShaper;
+
+    fn deref(&self) -> &Self::Target {
+        self.shaper
+    }
+}
+
+impl DerefMut for ShapingGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.shaper
+    }
+}
+
+impl Drop for ShapingGuard<'_> {
+    fn drop(&mut self) {
+        if self.armed {
+            if let Err(e) = self.shaper.cleanup() {
+                error!("failed to clean up traffic shaping: {}", e);
+            }
+        }
     }
 }
 
 #[derive(Serialize)]
 struct EventReport {
     now: DateTime<Local>,
-    bandwidth: u64,
-    latency: u32,
-    packet_loss: f32,
+    rule: String,
+    inbound: LinkConditions,
+    outbound: LinkConditions,
 }
 
 impl EventReport {
-    fn new(bandwidth: u64, latency: u32, packet_loss: f32) -> Self {
+    fn new(rule: &str, config: &ApplyConfig) -> Self {
         EventReport {
             now: Local::now(),
-            bandwidth,
-            latency,
-            packet_loss,
+            rule: rule.to_string(),
+            inbound: config.inbound.clone(),
+            outbound: config.outbound.clone(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    /// A shaper driving the pf backend through `fake`, its session kept in
+    /// a file of the test
+    fn shaper(fake: &Arc<FakeRunner>, test: &str) -> (TrafficShaper, PathBuf) {
+        let path = std::env::temp_dir().join(format!(
+            "traffic_shaper-guard-{}-{}.session",
+            test,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        fake.respond("pfctl -E", CommandOutput::success("Token : 42\n"));
+
+        let config = TrafficConfig::new(
+            1.0,
+            Delay::from_millis(50),
+            Bandwidth::from_bps(1_000_000),
+            Protocol::Udp,
+            None,
+            None,
+            Output::None,
+        )
+        .unwrap();
+        let backend = PfBackend::with_runner(fake.clone()).with_session_path(&path);
+        (TrafficShaper::with_backend(config, Box::new(backend)), path)
+    }
+
+    fn cleaned_up(fake: &FakeRunner) -> bool {
+        fake.command_lines().contains(&"pfctl -X 42".to_string())
+    }
+
+    #[test]
+    fn dropping_the_guard_cleans_up() {
+        let fake = Arc::new(FakeRunner::new());
+        let (mut shaper, path) = shaper(&fake, "dropping_the_guard_cleans_up");
+
+        let guard = shaper.enable().unwrap();
+        assert!(path.exists());
+        assert!(!cleaned_up(&fake));
+        drop(guard);
+        assert!(cleaned_up(&fake));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn a_panic_cleans_up() {
+        let fake = Arc::new(FakeRunner::new());
+        let (mut shaper, path) = shaper(&fake, "a_panic_cleans_up");
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _guard = shaper.enable().unwrap();
+            panic!("the test panicked while shaping");
+        }));
+        assert!(result.is_err());
+        assert!(cleaned_up(&fake));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn a_kept_guard_leaves_the_shaping() {
+        let fake = Arc::new(FakeRunner::new());
+        let (mut shaper, path) = shaper(&fake, "a_kept_guard_leaves_the_shaping");
+
+        shaper.enable().unwrap().keep();
+        assert!(!cleaned_up(&fake));
+        assert!(path.exists());
+
+        shaper.cleanup().unwrap();
+        assert!(cleaned_up(&fake));
+        assert!(!path.exists());
+    }
+}
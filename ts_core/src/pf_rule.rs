@@ -0,0 +1,483 @@
+use std::fmt;
+use std::iter::Peekable;
+use std::str::FromStr;
+use std::vec::IntoIter;
+
+use crate::{AddressFamily, Direction, IpNetwork, PortRange, TrafficShapingError};
+
+/// A PF dummynet rule, rendered in the syntax pfctl loads and parsed back
+/// from the syntax it lists:
+///
+/// ```text
+/// dummynet out quick on ! lo0 inet proto { tcp udp } from <clients> to 10.0.0.0/8 port 443 pipe 2
+/// no dummynet quick on lo0 all
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PfRule {
+    pub action: PfAction,
+    /// Both directions if unset
+    pub direction: Option<Direction>,
+    pub quick: bool,
+    pub interfaces: PfInterfaces,
+    pub family: AddressFamily,
+    /// Protocol names or numbers, any if empty
+    pub protocols: Vec<String>,
+    pub from: PfEndpoint,
+    pub to: PfEndpoint,
+}
+
+/// What a PF rule does with the packets it matches
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PfAction {
+    /// Sends them through a pipe or a queue
+    Dummynet(Dummynet),
+    /// Exempts them from the dummynet rules evaluated after this one
+    NoDummynet,
+}
+
+/// What a PF rule sends the packets it matches through: a pipe, or a
+/// queue sharing a pipe with other queues
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Dummynet {
+    Pipe(u32),
+    Queue(u32),
+}
+
+/// The `on` clause of a PF rule
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) enum PfInterfaces {
+    /// Every interface, with no `on` clause
+    #[default]
+    Any,
+    /// Only the listed interfaces
+    Only(Vec<String>),
+    /// Every interface but this one
+    Except(String),
+}
+
+/// The source or the destination of a PF rule
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct PfEndpoint {
+    pub hosts: PfHosts,
+    /// Any port if empty
+    pub ports: Vec<PortRange>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) enum PfHosts {
+    #[default]
+    Any,
+    Networks(Vec<IpNetwork>),
+    /// The addresses of a PF table
+    Table(String),
+}
+
+impl PfRule {
+    /// Creates a quick rule matching any packet in the given direction
+    pub fn new(action: PfAction, direction: Direction) -> Self {
+        Self {
+            action,
+            direction: Some(direction),
+            quick: true,
+            interfaces: PfInterfaces::Any,
+            family: AddressFamily::Both,
+            protocols: Vec::new(),
+            from: PfEndpoint::default(),
+            to: PfEndpoint::default(),
+        }
+    }
+}
+
+impl fmt::Display for PfRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.action {
+            PfAction::Dummynet(_) => write!(f, "dummynet")?,
+            PfAction::NoDummynet => write!(f, "no dummynet")?,
+        }
+        match self.direction {
+            Some(Direction::Inbound) => write!(f, " in")?,
+            Some(Direction::Outbound) => write!(f, " out")?,
+            None => {}
+        }
+        if self.quick {
+            write!(f, " quick")?;
+        }
+        match &self.interfaces {
+            PfInterfaces::Any => {}
+            PfInterfaces::Only(names) => write!(f, " on {}", list(names))?,
+            PfInterfaces::Except(name) => write!(f, " on ! {}", name)?,
+        }
+        match self.family {
+            AddressFamily::Inet => write!(f, " inet")?,
+            AddressFamily::Inet6 => write!(f, " inet6")?,
+            AddressFamily::Both => {}
+        }
+        if !self.protocols.is_empty() {
+            write!(f, " proto {}", list(&self.protocols))?;
+        }
+        if self.from.is_any() && self.to.is_any() {
+            write!(f, " all")?;
+        } else {
+            write!(f, " from {} to {}", self.from, self.to)?;
+        }
+        if let PfAction::Dummynet(target) = self.action {
+            write!(f, " {}", target)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Dummynet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Dummynet::Pipe(pipe) => write!(f, "pipe {}", pipe),
+            Dummynet::Queue(queue) => write!(f, "queue {}", queue),
+        }
+    }
+}
+
+impl PfEndpoint {
+    fn is_any(&self) -> bool {
+        self.hosts == PfHosts::Any && self.ports.is_empty()
+    }
+}
+
+/// Renders the hosts and the ports of the endpoint, leaving `any` out when
+/// only the ports are restricted
+impl fmt::Display for PfEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.hosts {
+            PfHosts::Any if self.ports.is_empty() => write!(f, "any")?,
+            PfHosts::Any => {}
+            PfHosts::Networks(networks) => {
+                let networks: Vec<String> = networks.iter().map(ToString::to_string).collect();
+                write!(f, "{}", list(&networks))?;
+                if !self.ports.is_empty() {
+                    write!(f, " ")?;
+                }
+            }
+            PfHosts::Table(name) => {
+                write!(f, "<{}>", name)?;
+                if !self.ports.is_empty() {
+                    write!(f, " ")?;
+                }
+            }
+        }
+        if !self.ports.is_empty() {
+            let ports: Vec<String> = self
+                .ports
+                .iter()
+                .map(|range| match range.start == range.end {
+                    true => range.start.to_string(),
+                    false => format!("{}:{}", range.start, range.end),
+                })
+                .collect();
+            write!(f, "port {}", list(&ports))?;
+        }
+        Ok(())
+    }
+}
+
+/// Renders a single item as is, and several as a PF list
+fn list(items: &[String]) -> String {
+    match items {
+        [item] => item.clone(),
+        _ => format!("{{ {} }}", items.join(" ")),
+    }
+}
+
+impl FromStr for PfRule {
+    type Err = TrafficShapingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || TrafficShapingError::InvalidPfRule(s.to_string());
+
+        // Lists may be written without spaces around their braces, and with
+        // commas between their items
+        let spaced = s
+            .replace('{', " { ")
+            .replace('}', " } ")
+            .replace(',', " ")
+            .replace('!', " ! ");
+        let tokens: Vec<String> = spaced.split_whitespace().map(String::from).collect();
+        let mut tokens = tokens.into_iter().peekable();
+
+        let no = next_if(&mut tokens, "no");
+        if !next_if(&mut tokens, "dummynet") {
+            return Err(invalid());
+        }
+
+        let direction = if next_if(&mut tokens, "in") {
+            Some(Direction::Inbound)
+        } else if next_if(&mut tokens, "out") {
+            Some(Direction::Outbound)
+        } else {
+            None
+        };
+        let quick = next_if(&mut tokens, "quick");
+
+        let interfaces = if !next_if(&mut tokens, "on") {
+            PfInterfaces::Any
+        } else if next_if(&mut tokens, "!") {
+            PfInterfaces::Except(tokens.next().ok_or_else(invalid)?)
+        } else {
+            PfInterfaces::Only(parse_list(&mut tokens).ok_or_else(invalid)?)
+        };
+
+        let family = if next_if(&mut tokens, "inet") {
+            AddressFamily::Inet
+        } else if next_if(&mut tokens, "inet6") {
+            AddressFamily::Inet6
+        } else {
+            AddressFamily::Both
+        };
+
+        let protocols = if next_if(&mut tokens, "proto") {
+            parse_list(&mut tokens).ok_or_else(invalid)?
+        } else {
+            Vec::new()
+        };
+
+        let (from, to) = if next_if(&mut tokens, "all") {
+            (PfEndpoint::default(), PfEndpoint::default())
+        } else {
+            if !next_if(&mut tokens, "from") {
+                return Err(invalid());
+            }
+            let from = parse_endpoint(&mut tokens).ok_or_else(invalid)?;
+            if !next_if(&mut tokens, "to") {
+                return Err(invalid());
+            }
+            (from, parse_endpoint(&mut tokens).ok_or_else(invalid)?)
+        };
+
+        let action = if no {
+            PfAction::NoDummynet
+        } else {
+            let kind = tokens.next().ok_or_else(invalid)?;
+            let number = tokens
+                .next()
+                .and_then(|number| number.parse().ok())
+                .ok_or_else(invalid)?;
+            match kind.as_str() {
+                "pipe" => PfAction::Dummynet(Dummynet::Pipe(number)),
+                "queue" => PfAction::Dummynet(Dummynet::Queue(number)),
+                _ => return Err(invalid()),
+            }
+        };
+
+        if tokens.next().is_some() {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            action,
+            direction,
+            quick,
+            interfaces,
+            family,
+            protocols,
+            from,
+            to,
+        })
+    }
+}
+
+type Tokens = Peekable<IntoIter<String>>;
+
+/// Consumes the next token if it is `keyword`
+fn next_if(tokens: &mut Tokens, keyword: &str) -> bool {
+    tokens.next_if(|token| token == keyword).is_some()
+}
+
+/// Parses a single item, or a list of items between braces
+fn parse_list(tokens: &mut Tokens) -> Option<Vec<String>> {
+    if !next_if(tokens, "{") {
+        return tokens.next().map(|item| vec![item]);
+    }
+
+    let mut items = Vec::new();
+    loop {
+        match tokens.next()? {
+            token if token == "}" => break,
+            token => items.push(token),
+        }
+    }
+    (!items.is_empty()).then_some(items)
+}
+
+/// Parses the hosts and ports of an endpoint, `any` being optional when
+/// ports follow
+fn parse_endpoint(tokens: &mut Tokens) -> Option<PfEndpoint> {
+    let hosts = match tokens.peek()?.as_str() {
+        "any" => {
+            tokens.next();
+            PfHosts::Any
+        }
+        "port" => PfHosts::Any,
+        table if table.starts_with('<') => {
+            let name = table.strip_prefix('<')?.strip_suffix('>')?.to_string();
+            tokens.next();
+            PfHosts::Table(name)
+        }
+        _ => PfHosts::Networks(
+            parse_list(tokens)?
+                .iter()
+                .map(|network| network.parse().ok())
+                .collect::<Option<_>>()?,
+        ),
+    };
+
+    // pfctl lists a single port as `port = 80`
+    let ports = if next_if(tokens, "port") {
+        next_if(tokens, "=");
+        parse_list(tokens)?
+            .iter()
+            .map(|port| parse_port_range(port))
+            .collect::<Option<_>>()?
+    } else {
+        Vec::new()
+    };
+
+    Some(PfEndpoint { hosts, ports })
+}
+
+/// Parses a port, or a range of ports written `start:end`
+fn parse_port_range(s: &str) -> Option<PortRange> {
+    let (start, end) = match s.split_once(':') {
+        Some((start, end)) => (start.parse().ok()?, end.parse().ok()?),
+        None => {
+            let port = s.parse().ok()?;
+            (port, port)
+        }
+    };
+    PortRange::new(start, end).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(rule: PfRule) {
+        let rendered = rule.to_string();
+        assert_eq!(rendered.parse::<PfRule>().unwrap(), rule, "{}", rendered);
+    }
+
+    #[test]
+    fn round_trips_pipes_and_queues() {
+        round_trip(PfRule::new(
+            PfAction::Dummynet(Dummynet::Pipe(1)),
+            Direction::Outbound,
+        ));
+        round_trip(PfRule {
+            interfaces: PfInterfaces::Except("lo0".to_string()),
+            protocols: vec!["tcp".to_string(), "udp".to_string()],
+            ..PfRule::new(PfAction::Dummynet(Dummynet::Queue(12)), Direction::Inbound)
+        });
+    }
+
+    #[test]
+    fn round_trips_no_dummynet() {
+        round_trip(PfRule {
+            direction: None,
+            interfaces: PfInterfaces::Only(vec!["lo0".to_string()]),
+            ..PfRule::new(PfAction::NoDummynet, Direction::Inbound)
+        });
+    }
+
+    #[test]
+    fn round_trips_endpoints() {
+        round_trip(PfRule {
+            interfaces: PfInterfaces::Only(vec!["en0".to_string(), "en1".to_string()]),
+            family: AddressFamily::Inet,
+            protocols: vec!["udp".to_string()],
+            from: PfEndpoint {
+                hosts: PfHosts::Table("clients".to_string()),
+                ports: Vec::new(),
+            },
+            to: PfEndpoint {
+                hosts: PfHosts::Networks(vec![
+                    "10.0.0.0/8".parse().unwrap(),
+                    "192.168.1.0/24".parse().unwrap(),
+                ]),
+                ports: vec![PortRange::single(443)],
+            },
+            ..PfRule::new(PfAction::Dummynet(Dummynet::Pipe(2)), Direction::Outbound)
+        });
+        round_trip(PfRule {
+            family: AddressFamily::Inet6,
+            protocols: vec!["tcp".to_string()],
+            from: PfEndpoint {
+                hosts: PfHosts::Any,
+                ports: vec![PortRange::single(443), PortRange::new(5000, 5100).unwrap()],
+            },
+            to: PfEndpoint {
+                hosts: PfHosts::Networks(vec!["fd00::/8".parse().unwrap()]),
+                ports: Vec::new(),
+            },
+            ..PfRule::new(PfAction::Dummynet(Dummynet::Pipe(3)), Direction::Inbound)
+        });
+    }
+
+    #[test]
+    fn renders_the_rule_syntax() {
+        let rule = PfRule {
+            interfaces: PfInterfaces::Except("lo0".to_string()),
+            protocols: vec!["tcp".to_string(), "udp".to_string()],
+            to: PfEndpoint {
+                hosts: PfHosts::Any,
+                ports: vec![PortRange::single(443), PortRange::new(5000, 5100).unwrap()],
+            },
+            ..PfRule::new(PfAction::Dummynet(Dummynet::Pipe(1)), Direction::Inbound)
+        };
+        assert_eq!(
+            rule.to_string(),
+            "dummynet in quick on ! lo0 proto { tcp udp } from any to port { 443 5000:5100 } pipe 1"
+        );
+    }
+
+    #[test]
+    fn parses_the_forms_pfctl_lists() {
+        let rule: PfRule = "dummynet out quick proto tcp from any to any port = 80 pipe 1"
+            .parse()
+            .unwrap();
+        assert_eq!(rule.to.ports, [PortRange::single(80)]);
+        assert_eq!(rule.to.hosts, PfHosts::Any);
+
+        let rule: PfRule =
+            "dummynet in quick on ! lo0 proto udp from any port { 443, 5000:5100 } to any queue 4"
+                .parse()
+                .unwrap();
+        assert_eq!(
+            rule.from.ports,
+            [PortRange::single(443), PortRange::new(5000, 5100).unwrap()]
+        );
+        assert_eq!(rule.interfaces, PfInterfaces::Except("lo0".to_string()));
+        assert_eq!(rule.action, PfAction::Dummynet(Dummynet::Queue(4)));
+
+        let rule: PfRule = "dummynet out all pipe 7".parse().unwrap();
+        assert_eq!(
+            rule,
+            PfRule {
+                quick: false,
+                ..PfRule::new(PfAction::Dummynet(Dummynet::Pipe(7)), Direction::Outbound)
+            }
+        );
+    }
+
+    #[test]
+    fn refuses_other_lines() {
+        for line in [
+            "",
+            "pass out all",
+            "dummynet-anchor \"com.apple/*\" all",
+            "dummynet out all",
+            "dummynet out all pipe",
+            "dummynet out from any pipe 1",
+            "dummynet out all pipe 1 extra",
+            "dummynet out proto tcp from any to any port 5100:5000 pipe 1",
+        ] {
+            assert!(line.parse::<PfRule>().is_err(), "{}", line);
+        }
+    }
+}
@@ -1,62 +1,444 @@
-use std::fs;
+use serde::{Deserialize, Serialize};
 
-use crate::{Protocol, TrafficConfig, TrafficShapingError};
+use crate::pf_rule::{Dummynet, PfAction, PfEndpoint, PfHosts, PfInterfaces, PfRule};
+use crate::{
+    AddressFamily, Direction, Interfaces, IpNetwork, PortRange, Protocol, TrafficConfig,
+    TrafficShapingError,
+};
+
+/// The loopback interface of macOS
+const PF_LOOPBACK: &str = "lo0";
 
 pub(crate) struct RuleGenerator;
 
+/// The main PF ruleset, as listed by pfctl, which can be loaded back as is
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct PfRuleset {
+    /// `set` statements of the options pfctl lists
+    #[serde(default)]
+    pub options: String,
+    /// `table` statements of the tables and their addresses
+    #[serde(default)]
+    pub tables: String,
+    /// Translation rules, `nat` and `rdr`
+    pub translation: String,
+    /// Dummynet rules and anchors
+    pub dummynet: String,
+    /// Normalization and filter rules and anchors
+    pub filter: String,
+}
+
+impl PfRuleset {
+    /// Renders the ruleset in the order pfctl requires: options, tables,
+    /// normalization, translation, dummynet and filtering
+    pub fn render(&self) -> String {
+        let (scrub, filter): (Vec<&str>, Vec<&str>) = self
+            .filter
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .partition(|line| line.starts_with("scrub"));
+
+        let mut rules = String::new();
+        for line in Self::lines(&self.options)
+            .chain(Self::lines(&self.tables))
+            .chain(scrub)
+            .chain(Self::lines(&self.translation))
+            .chain(Self::lines(&self.dummynet))
+            .chain(filter)
+        {
+            rules.push_str(line);
+            rules.push('\n');
+        }
+        rules
+    }
+
+    fn lines(rules: &str) -> impl Iterator<Item = &str> {
+        rules.lines().filter(|line| !line.trim().is_empty())
+    }
+
+    /// Checks if the dummynet rules evaluate the given anchor, which may be
+    /// a wildcard like `com.apple/*`
+    pub fn has_dummynet_anchor(&self, anchor: &str) -> bool {
+        Self::lines(&self.dummynet).any(|line| Self::is_dummynet_anchor(line, anchor))
+    }
+
+    /// Returns the ruleset evaluating the given anchor after its own
+    /// dummynet rules
+    pub fn with_dummynet_anchor(&self, anchor: &str) -> Self {
+        let mut ruleset = self.without_dummynet_anchor(anchor);
+        ruleset
+            .dummynet
+            .push_str(&format!("dummynet-anchor \"{}\" all\n", anchor));
+        ruleset
+    }
+
+    /// Returns the ruleset without the rule evaluating the given anchor
+    pub fn without_dummynet_anchor(&self, anchor: &str) -> Self {
+        let dummynet: String = Self::lines(&self.dummynet)
+            .filter(|line| !Self::is_dummynet_anchor(line, anchor))
+            .map(|line| format!("{}\n", line))
+            .collect();
+        Self {
+            dummynet,
+            ..self.clone()
+        }
+    }
+
+    fn is_dummynet_anchor(line: &str, anchor: &str) -> bool {
+        line.trim()
+            .starts_with(&format!("dummynet-anchor \"{}\"", anchor))
+    }
+}
+
 impl RuleGenerator {
-    /// Generates PF rules while preserving existing rules from /etc/pf.conf
+    /// Generates the PF rules sending inbound and outbound traffic through
+    /// their own dummynet pipes or queues
     pub fn generate_pf_rules(
         config: &TrafficConfig,
-        pipe_num: u32,
+        inbound: Dummynet,
+        outbound: Dummynet,
     ) -> Result<String, TrafficShapingError> {
-        let proto = match config.protocol {
-            Protocol::Tcp => "tcp",
-            Protocol::Udp => "udp",
-            Protocol::Both => "proto { tcp udp }",
+        let protocols = match config.protocol {
+            Protocol::Both => vec!["tcp".to_string(), "udp".to_string()],
+            Protocol::Any => Vec::new(),
+            protocol => vec![protocol.to_string()],
         };
 
-        // Build the rule based on configuration
-        let mut rule = format!("dummynet in quick proto {} ", proto);
+        let family = Self::family(config)?;
+        Self::check_ports(config)?;
 
-        if let Some(src_ports) = &config.src_ports {
-            rule.push_str(&format!("from port {}:{} ", src_ports.start, src_ports.end));
-        } else {
-            rule.push_str(&format!("from any "));
+        // Both directions match the same traffic
+        let filter = |target, direction| PfRule {
+            family,
+            protocols: protocols.clone(),
+            from: Self::pf_endpoint(&config.src_addresses, &config.src_ports, family),
+            to: Self::pf_endpoint(&config.dst_addresses, &config.dst_ports, family),
+            ..PfRule::new(PfAction::Dummynet(target), direction)
+        };
+
+        let mut rules = String::new();
+        // Loopback traffic passes both rules, so it is only shaped outbound
+        if let Some(interfaces) = Self::pf_interfaces(&config.interfaces, false) {
+            let rule = PfRule {
+                interfaces,
+                ..filter(inbound, Direction::Inbound)
+            };
+            rules.push_str(&format!("{}\n", rule));
+        }
+        if let Some(interfaces) = Self::pf_interfaces(&config.interfaces, true) {
+            let rule = PfRule {
+                interfaces,
+                ..filter(outbound, Direction::Outbound)
+            };
+            rules.push_str(&format!("{}\n", rule));
         }
 
-        if let Some(dst_ports) = &config.dst_ports {
-            rule.push_str(&format!("to port {}:{} ", dst_ports.start, dst_ports.end));
+        Ok(rules)
+    }
+
+    /// Returns the interfaces a PF rule matches, or `None` if it would
+    /// match no interface. Inbound rules never match loopback.
+    fn pf_interfaces(interfaces: &Interfaces, with_loopback: bool) -> Option<PfInterfaces> {
+        match interfaces {
+            Interfaces::All if with_loopback => Some(PfInterfaces::Any),
+            Interfaces::All | Interfaces::NonLoopback => {
+                Some(PfInterfaces::Except(PF_LOOPBACK.to_string()))
+            }
+            Interfaces::Loopback if with_loopback => {
+                Some(PfInterfaces::Only(vec![PF_LOOPBACK.to_string()]))
+            }
+            Interfaces::Loopback => None,
+            Interfaces::Named(names) => {
+                let names: Vec<String> = names
+                    .iter()
+                    .filter(|name| with_loopback || !Interfaces::is_loopback(name))
+                    .cloned()
+                    .collect();
+                (!names.is_empty()).then_some(PfInterfaces::Only(names))
+            }
+        }
+    }
+
+    /// Returns the hosts and ports of one end of a PF rule, leaving out the
+    /// addresses of the families the rule does not match
+    fn pf_endpoint(
+        addresses: &[IpNetwork],
+        ports: &[PortRange],
+        family: AddressFamily,
+    ) -> PfEndpoint {
+        let addresses: Vec<IpNetwork> = addresses
+            .iter()
+            .filter(|address| family.includes(address.is_ipv4()))
+            .copied()
+            .collect();
+        PfEndpoint {
+            hosts: match addresses.is_empty() {
+                true => PfHosts::Any,
+                false => PfHosts::Networks(addresses),
+            },
+            ports: ports.to_vec(),
+        }
+    }
+
+    /// Returns the addresses of the given family, or `None` if the list
+    /// is restricted to the other family only
+    fn addresses_of(addresses: &[IpNetwork], ipv4: bool) -> Option<Vec<IpNetwork>> {
+        let matching: Vec<IpNetwork> = addresses
+            .iter()
+            .filter(|address| address.is_ipv4() == ipv4)
+            .copied()
+            .collect();
+
+        if matching.is_empty() && !addresses.is_empty() {
+            None
         } else {
-            rule.push_str(&format!("to any "));
+            Some(matching)
         }
+    }
 
-        // Add pipe number
-        rule.push_str(&format!("pipe {}\n", pipe_num));
+    /// Returns the address families the traffic may belong to, allowed by
+    /// its family, its protocol and both its source and destination
+    /// addresses. Fails if none is, like for ICMPv6 to an IPv4 network.
+    fn family(config: &TrafficConfig) -> Result<AddressFamily, TrafficShapingError> {
+        let matches_family = |ipv4| {
+            config.family.includes(ipv4)
+                && config.protocol.family().includes(ipv4)
+                && Self::addresses_of(&config.src_addresses, ipv4).is_some()
+                && Self::addresses_of(&config.dst_addresses, ipv4).is_some()
+        };
 
-        // Add the rule for outbound traffic as well
-        let out_rule = rule.replace("in", "out");
+        match (matches_family(true), matches_family(false)) {
+            (true, true) => Ok(AddressFamily::Both),
+            (true, false) => Ok(AddressFamily::Inet),
+            (false, true) => Ok(AddressFamily::Inet6),
+            (false, false) => Err(TrafficShapingError::MixedAddressFamilies),
+        }
+    }
 
-        let mut rules = String::new();
+    /// Fails if ports are set for a protocol without any, like ICMP
+    fn check_ports(config: &TrafficConfig) -> Result<(), TrafficShapingError> {
+        let has_ports = !config.src_ports.is_empty() || !config.dst_ports.is_empty();
+        if has_ports && !config.protocol.has_ports() {
+            return Err(TrafficShapingError::PortsWithoutTransport(config.protocol));
+        }
+        // Ranges built without `PortRange::new` are unchecked, and an
+        // inverted one would match nothing
+        for range in config.src_ports.iter().chain(&config.dst_ports) {
+            PortRange::new(range.start, range.end)?;
+        }
+        Ok(())
+    }
 
-        rules.push_str(&rule);
-        rules.push_str(&out_rule);
+    /// Generates the `tc filter` match specs steering the configured traffic
+    /// into the shaped band. Every spec starts after `parent <handle>`.
+    pub fn generate_tc_filters(config: &TrafficConfig) -> Result<Vec<String>, TrafficShapingError> {
+        // Any protocol is `None`, matched by a match on nothing as u32 needs one
+        let protos: Vec<Option<u8>> = match config.protocol.numbers() {
+            numbers if numbers.is_empty() => vec![None],
+            numbers => numbers.into_iter().map(Some).collect(),
+        };
 
-        Ok(rules)
+        // u32 can only match a port against a value and mask, so ranges are
+        // split into aligned blocks and every combination gets its own filter
+        let src_blocks = Self::ports_blocks(&config.src_ports);
+        let dst_blocks = Self::ports_blocks(&config.dst_ports);
+
+        let matched_family = Self::family(config)?;
+        Self::check_ports(config)?;
+
+        let mut filters = Vec::new();
+        let families = [("ip", "ip", true), ("ipv6", "ip6", false)];
+        for (prio, (family, selector, ipv4)) in families.iter().enumerate() {
+            // Skip the family if the family, protocol or addresses rule it out
+            if !matched_family.includes(*ipv4) {
+                continue;
+            }
+            let (Some(src_addresses), Some(dst_addresses)) = (
+                Self::addresses_of(&config.src_addresses, *ipv4),
+                Self::addresses_of(&config.dst_addresses, *ipv4),
+            ) else {
+                continue;
+            };
+
+            for proto in &protos {
+                let proto_match = match proto {
+                    Some(proto) => format!(" match {} protocol {} 0xff", selector, proto),
+                    None => " match u32 0 0".to_string(),
+                };
+                let base = format!("protocol {} prio {} u32{}", family, prio + 1, proto_match);
+
+                for src_addr in Self::address_matches(selector, "src", &src_addresses) {
+                    for dst_addr in Self::address_matches(selector, "dst", &dst_addresses) {
+                        for src in Self::port_matches(selector, "sport", &src_blocks) {
+                            for dst in Self::port_matches(selector, "dport", &dst_blocks) {
+                                filters.push(format!(
+                                    "{}{}{}{}{}",
+                                    base, src_addr, dst_addr, src, dst
+                                ));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(filters)
     }
 
-    pub fn generate_anchor_rules(name: &str) -> Result<String, TrafficShapingError> {
-        // First read existing pf.conf
-        let existing_rules =
-            fs::read_to_string("/etc/pf.conf").map_err(|e| TrafficShapingError::SystemError(e))?;
+    fn address_matches(selector: &str, field: &str, addresses: &[IpNetwork]) -> Vec<String> {
+        if addresses.is_empty() {
+            return vec![String::new()];
+        }
 
-        let mut rules = String::new();
+        addresses
+            .iter()
+            .map(|address| {
+                format!(
+                    " match {} {} {}/{}",
+                    selector,
+                    field,
+                    address.addr(),
+                    address.prefix()
+                )
+            })
+            .collect()
+    }
 
-        rules.push_str(&existing_rules);
-        rules.push_str("\n\n# Traffic shaping rules added by traffic_shaper\n");
-        // Add dummynet configuration
-        rules.push_str(&format!("dummynet-anchor \"{}\"\n", name));
-        rules.push_str(&format!("anchor \"{}\"\n\n", name));
-        Ok(rules)
+    fn port_matches(selector: &str, field: &str, blocks: &Option<Vec<(u16, u16)>>) -> Vec<String> {
+        match blocks {
+            Some(blocks) => blocks
+                .iter()
+                .map(|(port, mask)| format!(" match {} {} {} {:#06x}", selector, field, port, mask))
+                .collect(),
+            None => vec![String::new()],
+        }
+    }
+
+    /// Splits the port ranges into (port, mask) blocks, or `None` to match
+    /// any port
+    fn ports_blocks(ranges: &[PortRange]) -> Option<Vec<(u16, u16)>> {
+        if ranges.is_empty() {
+            return None;
+        }
+        Some(ranges.iter().flat_map(Self::port_blocks).collect())
+    }
+
+    /// Splits a port range into the minimal list of (port, mask) blocks
+    fn port_blocks(range: &PortRange) -> Vec<(u16, u16)> {
+        let mut blocks = Vec::new();
+        let mut start = range.start as u32;
+        let end = range.end as u32;
+
+        while start <= end {
+            let mut size = 1u32;
+            while start.is_multiple_of(size * 2) && start + size * 2 - 1 <= end {
+                size *= 2;
+            }
+            blocks.push((start as u16, (0x10000 - size) as u16));
+            start += size;
+        }
+
+        blocks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Bandwidth, Delay, Output};
+
+    fn config(protocol: Protocol) -> TrafficConfig {
+        TrafficConfig::new(
+            0.0,
+            Delay::from_millis(50),
+            Bandwidth::from_bps(1_000_000),
+            protocol,
+            None,
+            None,
+            Output::None,
+        )
+        .unwrap()
+    }
+
+    fn pf_rules(config: &TrafficConfig) -> String {
+        RuleGenerator::generate_pf_rules(config, Dummynet::Pipe(1), Dummynet::Pipe(2)).unwrap()
+    }
+
+    #[test]
+    fn pf_rules_of_any_traffic() {
+        assert_eq!(
+            pf_rules(&config(Protocol::Any)),
+            "dummynet in quick on ! lo0 all pipe 1\n\
+             dummynet out quick all pipe 2\n"
+        );
+    }
+
+    #[test]
+    fn pf_rules_of_ports() {
+        let config = config(Protocol::Both).with_dst_ports(vec![
+            PortRange::single(443),
+            PortRange::new(5000, 5100).unwrap(),
+        ]);
+        assert_eq!(
+            pf_rules(&config),
+            "dummynet in quick on ! lo0 proto { tcp udp } from any to port { 443 5000:5100 } pipe 1\n\
+             dummynet out quick proto { tcp udp } from any to port { 443 5000:5100 } pipe 2\n"
+        );
+    }
+
+    #[test]
+    fn pf_rules_of_addresses_and_family() {
+        let config = config(Protocol::Udp)
+            .with_src_ports(vec![PortRange::single(3478)])
+            .with_dst_addresses(vec![
+                "10.0.0.0/8".parse().unwrap(),
+                "fd00::/8".parse().unwrap(),
+            ])
+            .with_family(AddressFamily::Inet);
+        assert_eq!(
+            pf_rules(&config),
+            "dummynet in quick on ! lo0 inet proto udp from port 3478 to 10.0.0.0/8 pipe 1\n\
+             dummynet out quick inet proto udp from port 3478 to 10.0.0.0/8 pipe 2\n"
+        );
+    }
+
+    #[test]
+    fn pf_rules_of_interfaces_and_queues() {
+        let config = config(Protocol::Icmp6).with_interfaces(Interfaces::Named(vec![
+            "en0".to_string(),
+            "lo0".to_string(),
+        ]));
+        assert_eq!(
+            RuleGenerator::generate_pf_rules(&config, Dummynet::Queue(3), Dummynet::Queue(4))
+                .unwrap(),
+            "dummynet in quick on en0 inet6 proto icmp6 all queue 3\n\
+             dummynet out quick on { en0 lo0 } inet6 proto icmp6 all queue 4\n"
+        );
+
+        // Loopback traffic is only shaped outbound
+        let config = config.with_interfaces(Interfaces::Loopback);
+        assert_eq!(
+            pf_rules(&config),
+            "dummynet out quick on lo0 inet6 proto icmp6 all pipe 2\n"
+        );
+    }
+
+    #[test]
+    fn pf_rules_refuse_ports_without_transport() {
+        let config = config(Protocol::Icmp).with_dst_ports(vec![PortRange::single(80)]);
+        assert!(
+            RuleGenerator::generate_pf_rules(&config, Dummynet::Pipe(1), Dummynet::Pipe(2))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn pf_rules_parse_back() {
+        let config = config(Protocol::Tcp)
+            .with_src_addresses(vec!["192.168.1.0/24".parse().unwrap()])
+            .with_dst_ports(vec![PortRange::single(80)]);
+        for line in pf_rules(&config).lines() {
+            assert_eq!(line.parse::<PfRule>().unwrap().to_string(), line);
+        }
     }
 }
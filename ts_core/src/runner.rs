@@ -0,0 +1,343 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+
+use crate::TrafficShapingError;
+
+/// A single invocation of an external program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invocation {
+    pub program: String,
+    pub args: Vec<String>,
+    /// Data written to the standard input of the program
+    pub stdin: Option<String>,
+}
+
+impl Invocation {
+    pub fn new(program: &str) -> Self {
+        Self {
+            program: program.to_string(),
+            args: Vec::new(),
+            stdin: None,
+        }
+    }
+
+    pub fn arg(mut self, arg: impl Into<String>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn stdin(mut self, stdin: impl Into<String>) -> Self {
+        self.stdin = Some(stdin.into());
+        self
+    }
+}
+
+/// Renders the invocation as a command line, e.g. `dnctl pipe 1 config bw 1000bit/s`
+impl fmt::Display for Invocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.program)?;
+        for arg in &self.args {
+            write!(f, " {}", arg)?;
+        }
+        Ok(())
+    }
+}
+
+/// The result of running an `Invocation`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandOutput {
+    pub status: i32,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl CommandOutput {
+    /// A successful run printing `stdout`
+    pub fn success(stdout: &str) -> Self {
+        Self {
+            status: 0,
+            stdout: stdout.to_string(),
+            stderr: String::new(),
+        }
+    }
+
+    /// A failed run exiting with `status` and printing `stderr`
+    pub fn failure(status: i32, stderr: &str) -> Self {
+        Self {
+            status,
+            stdout: String::new(),
+            stderr: stderr.to_string(),
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.status == 0
+    }
+}
+
+/// Executes the external programs the command layer relies on
+pub trait CommandRunner: Send + Sync {
+    fn run(&self, invocation: &Invocation) -> Result<CommandOutput, TrafficShapingError>;
+
+    /// Runs the invocation and turns a non-zero exit status into an error
+    fn run_checked(&self, invocation: &Invocation) -> Result<CommandOutput, TrafficShapingError> {
+        let output = self.run(invocation)?;
+
+        if !output.is_success() {
+            return Err(TrafficShapingError::CommandError(output.stderr));
+        }
+
+        Ok(output)
+    }
+}
+
+/// Runs invocations as child processes
+#[derive(Debug, Default)]
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(&self, invocation: &Invocation) -> Result<CommandOutput, TrafficShapingError> {
+        let mut cmd = Command::new(&invocation.program);
+        cmd.args(&invocation.args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        if invocation.stdin.is_some() {
+            cmd.stdin(Stdio::piped());
+        }
+
+        let mut child = cmd.spawn()?;
+        if let (Some(stdin), Some(mut pipe)) = (&invocation.stdin, child.stdin.take()) {
+            pipe.write_all(stdin.as_bytes())?;
+        }
+
+        let output = child.wait_with_output()?;
+        Ok(CommandOutput {
+            // Killed by a signal when there is no exit code
+            status: output.status.code().unwrap_or(-1),
+            stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+        })
+    }
+}
+
+/// Records every invocation instead of running it and answers with scripted
+/// outputs, so the exact command sequences can be asserted without root.
+#[derive(Debug, Default)]
+pub struct FakeRunner {
+    invocations: Mutex<Vec<Invocation>>,
+    responses: Mutex<Vec<(String, VecDeque<CommandOutput>)>>,
+}
+
+impl FakeRunner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Scripts the output of invocations whose command line starts with
+    /// `prefix`. Outputs scripted for the same prefix are returned in order,
+    /// the last one repeating. Unscripted invocations succeed silently.
+    pub fn respond(&self, prefix: &str, output: CommandOutput) {
+        let mut responses = self.responses.lock().unwrap();
+        match responses.iter_mut().find(|(p, _)| p == prefix) {
+            Some((_, outputs)) => outputs.push_back(output),
+            None => responses.push((prefix.to_string(), VecDeque::from([output]))),
+        }
+    }
+
+    /// Returns the invocations recorded so far, in order
+    pub fn invocations(&self) -> Vec<Invocation> {
+        self.invocations.lock().unwrap().clone()
+    }
+
+    /// Returns the command lines recorded so far, in order
+    pub fn command_lines(&self) -> Vec<String> {
+        self.invocations().iter().map(ToString::to_string).collect()
+    }
+
+    /// Forgets the recorded invocations, keeping the scripted outputs
+    pub fn clear(&self) {
+        self.invocations.lock().unwrap().clear();
+    }
+}
+
+impl CommandRunner for FakeRunner {
+    fn run(&self, invocation: &Invocation) -> Result<CommandOutput, TrafficShapingError> {
+        self.invocations.lock().unwrap().push(invocation.clone());
+
+        let line = invocation.to_string();
+        let mut responses = self.responses.lock().unwrap();
+        // The longest matching prefix is the most specific script
+        let outputs = responses
+            .iter_mut()
+            .filter(|(prefix, _)| line.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, outputs)| outputs);
+
+        Ok(match outputs {
+            Some(outputs) if outputs.len() > 1 => outputs.pop_front().unwrap(),
+            Some(outputs) => outputs[0].clone(),
+            None => CommandOutput::success(""),
+        })
+    }
+}
+
+/// Prints every invocation instead of running it, to stdout by default, with
+/// the data written to its standard input as a heredoc. Answers like
+/// `FakeRunner`, except for the read-only invocations passed through to the
+/// system.
+pub struct DryRunRunner {
+    responses: FakeRunner,
+    pass_through: Vec<String>,
+    output: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Default for DryRunRunner {
+    fn default() -> Self {
+        Self {
+            responses: FakeRunner::default(),
+            pass_through: Vec::new(),
+            output: Mutex::new(Box::new(std::io::stdout())),
+        }
+    }
+}
+
+impl fmt::Debug for DryRunRunner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DryRunRunner")
+            .field("responses", &self.responses)
+            .field("pass_through", &self.pass_through)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DryRunRunner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Prints the invocations to `output` instead of stdout
+    pub fn with_output(mut self, output: impl Write + Send + 'static) -> Self {
+        self.output = Mutex::new(Box::new(output));
+        self
+    }
+
+    /// Scripts the output of the printed invocations whose command line
+    /// starts with `prefix`, as `FakeRunner::respond` does
+    pub fn respond(&self, prefix: &str, output: CommandOutput) {
+        self.responses.respond(prefix, output);
+    }
+
+    /// Runs the invocations whose command line starts with `prefix` instead
+    /// of printing them. Only for commands that change nothing.
+    pub fn pass_through(mut self, prefix: &str) -> Self {
+        self.pass_through.push(prefix.to_string());
+        self
+    }
+}
+
+impl CommandRunner for DryRunRunner {
+    fn run(&self, invocation: &Invocation) -> Result<CommandOutput, TrafficShapingError> {
+        let line = invocation.to_string();
+        if self
+            .pass_through
+            .iter()
+            .any(|prefix| line.starts_with(prefix.as_str()))
+        {
+            return SystemRunner.run(invocation);
+        }
+
+        let mut output = self.output.lock().unwrap();
+        match &invocation.stdin {
+            Some(stdin) if stdin.ends_with('\n') => {
+                writeln!(output, "{} <<EOF\n{}EOF", line, stdin)?
+            }
+            Some(stdin) => writeln!(output, "{} <<EOF\n{}\nEOF", line, stdin)?,
+            None => writeln!(output, "{}", line)?,
+        }
+        self.responses.run(invocation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    /// Output shared with the runner printing into it
+    #[derive(Clone, Default)]
+    struct Printed(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Printed {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Printed {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    #[test]
+    fn dry_run_prints_the_invocations_in_order() {
+        let printed = Printed::default();
+        let runner = DryRunRunner::new().with_output(printed.clone());
+        runner.respond("pfctl -E", CommandOutput::success("Token : 42\n"));
+
+        // Programs that do not exist would fail if they were run
+        let output = runner
+            .run(&Invocation::new("traffic-shaper-missing").arg("-E"))
+            .unwrap();
+        assert_eq!(output, CommandOutput::success(""));
+        let output = runner.run(&Invocation::new("pfctl").arg("-E")).unwrap();
+        assert_eq!(output.stdout, "Token : 42\n");
+        runner
+            .run(&Invocation::new("pfctl").args(["-f", "-"]).stdin("pass all"))
+            .unwrap();
+        runner
+            .run(
+                &Invocation::new("pfctl")
+                    .args(["-a", "x", "-f", "-"])
+                    .stdin("block all\n"),
+            )
+            .unwrap();
+
+        assert_eq!(
+            printed.text(),
+            "traffic-shaper-missing -E\n\
+             pfctl -E\n\
+             pfctl -f - <<EOF\npass all\nEOF\n\
+             pfctl -a x -f - <<EOF\nblock all\nEOF\n"
+        );
+    }
+
+    #[test]
+    fn dry_run_runs_the_invocations_passed_through() {
+        let printed = Printed::default();
+        let runner = DryRunRunner::new()
+            .with_output(printed.clone())
+            .pass_through("echo");
+
+        let output = runner.run(&Invocation::new("echo").arg("listed")).unwrap();
+        assert_eq!(output.stdout, "listed\n");
+        assert_eq!(printed.text(), "");
+    }
+}
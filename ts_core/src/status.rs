@@ -0,0 +1,435 @@
+use std::net::IpAddr;
+
+use serde::Serialize;
+
+use crate::{Bandwidth, Delay, QueueSize, Red};
+
+/// The pipes and queues configured in dummynet, as listed by `dnctl show`
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DummynetStatus {
+    pub pipes: Vec<PipeStatus>,
+    pub queues: Vec<QueueStatus>,
+}
+
+/// A dummynet pipe and the traffic that went through it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PipeStatus {
+    pub number: u32,
+    pub bandwidth: Bandwidth,
+    pub delay: Delay,
+    /// Packet loss rate, as a ratio (0.0 to 1.0)
+    pub plr: f32,
+    pub queue: Option<QueueSize>,
+    pub red: Option<Red>,
+    /// A pipe has a single flow, unless a mask splits its traffic
+    pub flows: Vec<FlowStatus>,
+}
+
+/// A dummynet queue feeding a pipe, and the traffic that went through it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QueueStatus {
+    pub number: u32,
+    /// Number of the pipe the queue feeds
+    pub pipe: u32,
+    pub weight: u32,
+    /// Packet loss rate, as a ratio (0.0 to 1.0)
+    pub plr: f32,
+    pub queue: Option<QueueSize>,
+    pub red: Option<Red>,
+    pub flows: Vec<FlowStatus>,
+}
+
+/// The counters of one flow of a pipe or a queue. The fields left out by
+/// the mask are zero.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FlowStatus {
+    /// Bucket of the flow in the hash table of the pipe or queue
+    pub bucket: u32,
+    /// Protocol name, or number if it has none
+    pub protocol: String,
+    pub src_addr: IpAddr,
+    pub src_port: u16,
+    pub dst_addr: IpAddr,
+    pub dst_port: u16,
+    /// Packets and bytes that entered the flow
+    pub packets: u64,
+    pub bytes: u64,
+    /// Packets and bytes currently queued
+    pub queued_packets: u64,
+    pub queued_bytes: u64,
+    /// Packets dropped by the queue, RED or the packet loss rate
+    pub drops: u64,
+}
+
+impl PipeStatus {
+    /// Packets that entered the pipe, over all its flows
+    pub fn packets(&self) -> u64 {
+        self.flows.iter().map(|flow| flow.packets).sum()
+    }
+
+    /// Bytes that entered the pipe, over all its flows
+    pub fn bytes(&self) -> u64 {
+        self.flows.iter().map(|flow| flow.bytes).sum()
+    }
+
+    /// Packets dropped by the pipe, over all its flows
+    pub fn drops(&self) -> u64 {
+        self.flows.iter().map(|flow| flow.drops).sum()
+    }
+}
+
+impl QueueStatus {
+    /// Packets that entered the queue, over all its flows
+    pub fn packets(&self) -> u64 {
+        self.flows.iter().map(|flow| flow.packets).sum()
+    }
+
+    /// Bytes that entered the queue, over all its flows
+    pub fn bytes(&self) -> u64 {
+        self.flows.iter().map(|flow| flow.bytes).sum()
+    }
+
+    /// Packets dropped by the queue, over all its flows
+    pub fn drops(&self) -> u64 {
+        self.flows.iter().map(|flow| flow.drops).sum()
+    }
+}
+
+impl DummynetStatus {
+    /// Parses the output of `dnctl show`. Every pipe or queue is a header
+    /// line, an optional RED line, its mask, and a line per flow:
+    ///
+    /// ```text
+    /// 00001:   1.000 Mbit/s   50 ms   50 sl.plr 0.010000 1 queues (1 buckets) droptail
+    ///     mask: 0x00 0x00000000/0x0000 -> 0x00000000/0x0000
+    /// BKT Prot ___Source IP/port____ ____Dest. IP/port____ Tot_pkt/bytes Pkt/Byte Drp
+    ///   0 udp        10.0.0.1/5000         10.0.0.2/6000       120    14400  0    0   1
+    /// q00002: weight 30 pipe 2  50 sl. 0 queues (64 buckets)
+    ///           RED w_q 0.002000 min_th 5 max_th 15 max_p 0.100000
+    /// ```
+    ///
+    /// Lines that match none of these are skipped.
+    pub(crate) fn parse(output: &str) -> Self {
+        let mut status = Self::default();
+        // Whether the last header was a queue, which the lines after it belong to
+        let mut in_queue = false;
+
+        for line in output.lines() {
+            if let Some((num, rest)) = line.split_once(':') {
+                if let Some(num) = num.strip_prefix('q').and_then(parse_number) {
+                    status.queues.push(parse_queue(num, rest));
+                    in_queue = true;
+                    continue;
+                }
+                if let Some(num) = parse_number(num) {
+                    status.pipes.push(parse_pipe(num, rest));
+                    in_queue = false;
+                    continue;
+                }
+            }
+
+            let (red, flows) = match (in_queue, status.pipes.last_mut(), status.queues.last_mut()) {
+                (false, Some(pipe), _) => (&mut pipe.red, &mut pipe.flows),
+                (true, _, Some(queue)) => (&mut queue.red, &mut queue.flows),
+                _ => continue,
+            };
+            let tokens: Vec<&str> = line.split_whitespace().collect();
+            if let Some(parsed) = parse_red(&tokens) {
+                *red = Some(parsed);
+            } else if let Some(flow) = parse_flow(&tokens) {
+                flows.push(flow);
+            }
+        }
+
+        status
+    }
+}
+
+/// Parses a pipe or queue number, made of digits only
+fn parse_number(s: &str) -> Option<u32> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    s.parse().ok()
+}
+
+/// Splits a header into tokens. dnctl prints the loss rate right after the
+/// queue size, as in `50 sl.plr 0.010000`.
+fn header_tokens(header: &str) -> Vec<&str> {
+    header
+        .split_whitespace()
+        .flat_map(|token| match token.strip_prefix("sl.") {
+            Some("") | None => vec![token],
+            Some(rest) => vec!["sl.", rest],
+        })
+        .collect()
+}
+
+/// Returns the token following `key`, parsed
+fn value_after<T: std::str::FromStr>(tokens: &[&str], key: &str) -> Option<T> {
+    let idx = tokens.iter().position(|token| *token == key)?;
+    tokens.get(idx + 1)?.parse().ok()
+}
+
+/// Returns the token preceding `key`, parsed
+fn value_before<T: std::str::FromStr>(tokens: &[&str], key: &str) -> Option<T> {
+    let idx = tokens.iter().position(|token| *token == key)?;
+    tokens.get(idx.checked_sub(1)?)?.parse().ok()
+}
+
+/// Parses the queue size, printed in slots as `50 sl.` or in kilobytes as
+/// `64 KB`
+fn parse_queue_size(tokens: &[&str]) -> Option<QueueSize> {
+    if let Some(slots) = value_before(tokens, "sl.") {
+        return Some(QueueSize::Slots(slots));
+    }
+    value_before::<u64>(tokens, "KB").map(|kilobytes| QueueSize::Bytes(kilobytes * 1024))
+}
+
+/// Parses the bandwidth at the start of a pipe header, like `1.000 Mbit/s`
+/// or `unlimited`
+fn parse_bandwidth(tokens: &[&str]) -> Bandwidth {
+    let (Some(value), Some(unit)) = (tokens.first(), tokens.get(1)) else {
+        return Bandwidth::UNLIMITED;
+    };
+    format!("{}{}", value, unit)
+        .parse()
+        .unwrap_or(Bandwidth::UNLIMITED)
+}
+
+fn parse_pipe(number: u32, header: &str) -> PipeStatus {
+    let tokens = header_tokens(header);
+    PipeStatus {
+        number,
+        bandwidth: parse_bandwidth(&tokens),
+        delay: Delay::from_millis(value_before(&tokens, "ms").unwrap_or(0)),
+        plr: value_after(&tokens, "plr").unwrap_or(0.0),
+        queue: parse_queue_size(&tokens),
+        red: None,
+        flows: Vec::new(),
+    }
+}
+
+fn parse_queue(number: u32, header: &str) -> QueueStatus {
+    let tokens = header_tokens(header);
+    QueueStatus {
+        number,
+        pipe: value_after(&tokens, "pipe").unwrap_or(0),
+        weight: value_after(&tokens, "weight").unwrap_or(0),
+        plr: value_after(&tokens, "plr").unwrap_or(0.0),
+        queue: parse_queue_size(&tokens),
+        red: None,
+        flows: Vec::new(),
+    }
+}
+
+/// Parses a line like `RED w_q 0.002000 min_th 5 max_th 15 max_p 0.100000`,
+/// `GRED` for gentle RED
+fn parse_red(tokens: &[&str]) -> Option<Red> {
+    let gentle = match *tokens.first()? {
+        "RED" => false,
+        "GRED" => true,
+        _ => return None,
+    };
+    Some(Red {
+        weight: value_after(tokens, "w_q")?,
+        min_threshold: value_after(tokens, "min_th")?,
+        max_threshold: value_after(tokens, "max_th")?,
+        max_probability: value_after(tokens, "max_p")?,
+        gentle,
+    })
+}
+
+/// Parses the line of a flow. IPv6 flows have a flow id column after the
+/// protocol, which is skipped.
+fn parse_flow(tokens: &[&str]) -> Option<FlowStatus> {
+    let (bucket, protocol, rest) = match tokens {
+        [bucket, protocol, _, rest @ ..] if rest.len() == 7 => (bucket, protocol, rest),
+        [bucket, protocol, rest @ ..] if rest.len() == 7 => (bucket, protocol, rest),
+        _ => return None,
+    };
+    let (src_addr, src_port) = parse_endpoint(rest[0])?;
+    let (dst_addr, dst_port) = parse_endpoint(rest[1])?;
+
+    Some(FlowStatus {
+        bucket: bucket.parse().ok()?,
+        protocol: protocol.to_string(),
+        src_addr,
+        src_port,
+        dst_addr,
+        dst_port,
+        packets: rest[2].parse().ok()?,
+        bytes: rest[3].parse().ok()?,
+        queued_packets: rest[4].parse().ok()?,
+        queued_bytes: rest[5].parse().ok()?,
+        drops: rest[6].parse().ok()?,
+    })
+}
+
+/// Parses an `address/port` column
+fn parse_endpoint(s: &str) -> Option<(IpAddr, u16)> {
+    let (addr, port) = s.rsplit_once('/')?;
+    Some((addr.parse().ok()?, port.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = "\
+00001:   1.000 Mbit/s   50 ms   50 sl.plr 0.010000 1 queues (1 buckets) droptail
+    mask: 0x00 0x00000000/0x0000 -> 0x00000000/0x0000
+BKT Prot ___Source IP/port____ ____Dest. IP/port____ Tot_pkt/bytes Pkt/Byte Drp
+  0 udp        10.0.0.1/5000         10.0.0.2/6000       120    14400  0    0   1
+q00002: weight 30 pipe 2  50 sl. 0 queues (64 buckets)
+          RED w_q 0.002000 min_th 5 max_th 15 max_p 0.100000
+";
+
+    fn flow(src: &str, dst: &str) -> FlowStatus {
+        let (src_addr, src_port) = parse_endpoint(src).unwrap();
+        let (dst_addr, dst_port) = parse_endpoint(dst).unwrap();
+        FlowStatus {
+            bucket: 0,
+            protocol: "udp".to_string(),
+            src_addr,
+            src_port,
+            dst_addr,
+            dst_port,
+            packets: 0,
+            bytes: 0,
+            queued_packets: 0,
+            queued_bytes: 0,
+            drops: 0,
+        }
+    }
+
+    #[test]
+    fn parses_the_documented_sample() {
+        let status = DummynetStatus::parse(SAMPLE);
+
+        assert_eq!(
+            status.pipes,
+            [PipeStatus {
+                number: 1,
+                bandwidth: Bandwidth::from_bps(1_000_000),
+                delay: Delay::from_millis(50),
+                plr: 0.01,
+                queue: Some(QueueSize::Slots(50)),
+                red: None,
+                flows: vec![FlowStatus {
+                    packets: 120,
+                    bytes: 14400,
+                    drops: 1,
+                    ..flow("10.0.0.1/5000", "10.0.0.2/6000")
+                }],
+            }]
+        );
+        assert_eq!(
+            status.queues,
+            [QueueStatus {
+                number: 2,
+                pipe: 2,
+                weight: 30,
+                plr: 0.0,
+                queue: Some(QueueSize::Slots(50)),
+                red: Some(Red {
+                    weight: 0.002,
+                    min_threshold: 5,
+                    max_threshold: 15,
+                    max_probability: 0.1,
+                    gentle: false,
+                }),
+                flows: Vec::new(),
+            }]
+        );
+    }
+
+    #[test]
+    fn splits_the_loss_rate_from_the_queue_size() {
+        assert_eq!(
+            header_tokens(" 50 sl.plr 0.010000 droptail"),
+            ["50", "sl.", "plr", "0.010000", "droptail"]
+        );
+        assert_eq!(
+            header_tokens(" 50 sl. 0 queues"),
+            ["50", "sl.", "0", "queues"]
+        );
+    }
+
+    #[test]
+    fn parses_unlimited_pipes_and_byte_queues() {
+        let status = DummynetStatus::parse(
+            "00003: unlimited    0 ms   64 KB 1 queues (1 buckets) droptail\n\
+             00004:  10.000 Mbit/s    0 ms   50 sl.plr 0.500000 0 queues (1 buckets)\n\
+             \x20         GRED w_q 0.002000 min_th 5 max_th 15 max_p 0.100000\n",
+        );
+
+        let [unlimited, lossy] = &status.pipes[..] else {
+            panic!("{:?}", status);
+        };
+        assert_eq!(unlimited.bandwidth, Bandwidth::UNLIMITED);
+        assert_eq!(unlimited.delay, Delay::ZERO);
+        assert_eq!(unlimited.queue, Some(QueueSize::Bytes(64 * 1024)));
+        assert_eq!(unlimited.red, None);
+        assert_eq!(lossy.bandwidth, Bandwidth::from_bps(10_000_000));
+        assert_eq!(lossy.plr, 0.5);
+        assert!(lossy.red.as_ref().is_some_and(|red| red.gentle));
+        assert!(status.queues.is_empty());
+    }
+
+    #[test]
+    fn parses_ipv6_flows() {
+        let status = DummynetStatus::parse(
+            "00001:   1.000 Mbit/s   50 ms   50 sl. 2 queues (64 buckets) droptail\n\
+             \x20   mask: 0x11 0x00000000/0xffff -> 0x00000000/0xffff\n\
+             BKT ___Prot___ _flow-id_ ______________Source IPv6/port_______________ \
+             _______________Dest. IPv6/port_______________ Tot_pkt/bytes Pkt/Byte Drp\n\
+             \x20 12 udp      0 fe80::1/5000 fd00::2/6000 7 840 1 120 0\n\
+             \x20 13 udp      10.0.0.1/5001 10.0.0.2/6001 3 360 0 0 2\n",
+        );
+
+        assert_eq!(
+            status.pipes[0].flows,
+            [
+                FlowStatus {
+                    bucket: 12,
+                    packets: 7,
+                    bytes: 840,
+                    queued_packets: 1,
+                    queued_bytes: 120,
+                    ..flow("fe80::1/5000", "fd00::2/6000")
+                },
+                FlowStatus {
+                    bucket: 13,
+                    packets: 3,
+                    bytes: 360,
+                    drops: 2,
+                    ..flow("10.0.0.1/5001", "10.0.0.2/6001")
+                },
+            ]
+        );
+        assert_eq!(status.pipes[0].packets(), 10);
+        assert_eq!(status.pipes[0].drops(), 2);
+    }
+
+    #[test]
+    fn flows_belong_to_the_last_header() {
+        let status = DummynetStatus::parse(
+            "q00005: weight 70 pipe 1  50 sl. 1 queues (1 buckets)\n\
+             \x20 0 udp        10.0.0.1/5000         10.0.0.2/6000       4    480  0    0   0\n\
+             00001:   1.000 Mbit/s    0 ms   50 sl. 0 queues (1 buckets) droptail\n",
+        );
+
+        assert_eq!(status.queues[0].weight, 70);
+        assert_eq!(status.queues[0].packets(), 4);
+        assert!(status.pipes[0].flows.is_empty());
+    }
+
+    #[test]
+    fn skips_other_lines() {
+        assert_eq!(
+            DummynetStatus::parse("dnctl: unknown option\n\nsomething: else\n"),
+            DummynetStatus::default()
+        );
+    }
+}
@@ -0,0 +1,338 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::TrafficShapingError;
+
+/// A bandwidth in bits per second, 0 standing for unlimited
+///
+/// Parsed from a number of bits per second, or a value and a unit: `10Mbit`,
+/// `512kbps` and `1Gbit/s` in bits, `1.5MB/s` and `64KiB` in bytes. Prefixes
+/// are decimal, `Ki`, `Mi` and `Gi` binary, and `bps` counts bits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+#[serde(try_from = "UnitSpec")]
+pub struct Bandwidth(u64);
+
+impl Bandwidth {
+    pub const UNLIMITED: Self = Self(0);
+
+    pub fn from_bps(bits_per_second: u64) -> Self {
+        Self(bits_per_second)
+    }
+
+    /// Bits per second, 0 if unlimited
+    pub fn bps(self) -> u64 {
+        self.0
+    }
+
+    pub fn is_unlimited(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl FromStr for Bandwidth {
+    type Err = TrafficShapingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || TrafficShapingError::InvalidBandwidth(s.to_string());
+        if s.trim() == "unlimited" {
+            return Ok(Self::UNLIMITED);
+        }
+
+        let (value, unit) = split_unit(s).ok_or_else(invalid)?;
+        let unit = unit.strip_suffix("/s").unwrap_or(unit);
+        let (power, unit) = match unit.chars().next() {
+            Some('k' | 'K') => (1, &unit[1..]),
+            Some('m' | 'M') => (2, &unit[1..]),
+            Some('g' | 'G') => (3, &unit[1..]),
+            _ => (0, unit),
+        };
+        let (base, unit) = match unit.strip_prefix('i') {
+            Some(unit) if power > 0 => (1_024f64, unit),
+            _ => (1_000f64, unit),
+        };
+        let bits = match unit {
+            "" | "b" | "bps" => 1.0,
+            "B" | "Bps" => 8.0,
+            _ => match unit.to_lowercase().as_str() {
+                "bit" | "bits" => 1.0,
+                "byte" | "bytes" => 8.0,
+                _ => return Err(invalid()),
+            },
+        };
+
+        // 0 stands for unlimited, which a tiny bandwidth must not become
+        let bps = (value * base.powi(power) * bits).round() as u64;
+        if bps == 0 && value > 0.0 {
+            return Err(invalid());
+        }
+        Ok(Self(bps))
+    }
+}
+
+/// Renders the bandwidth in the largest decimal unit dividing it, like
+/// `10Mbit`, or `unlimited`
+impl fmt::Display for Bandwidth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_unlimited() {
+            return write!(f, "unlimited");
+        }
+        let units = [
+            (1_000_000_000, "Gbit"),
+            (1_000_000, "Mbit"),
+            (1_000, "Kbit"),
+        ];
+        match units
+            .iter()
+            .find(|(scale, _)| self.0.is_multiple_of(*scale))
+        {
+            Some((scale, unit)) => write!(f, "{}{}", self.0 / scale, unit),
+            None => write!(f, "{}bit", self.0),
+        }
+    }
+}
+
+/// A delay, like the latency of a link or its jitter
+///
+/// Parsed from a number of milliseconds, or a value and a unit: `250ms`,
+/// `1.2s`, `500us`, `1min`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+#[serde(try_from = "UnitSpec")]
+pub struct Delay(Duration);
+
+impl Delay {
+    pub const ZERO: Self = Self(Duration::ZERO);
+
+    pub fn from_millis(millis: u64) -> Self {
+        Self(Duration::from_millis(millis))
+    }
+
+    /// Whole milliseconds, rounded down
+    pub fn as_millis(self) -> u64 {
+        self.0.as_millis() as u64
+    }
+
+    pub fn as_duration(self) -> Duration {
+        self.0
+    }
+}
+
+impl From<Duration> for Delay {
+    fn from(duration: Duration) -> Self {
+        Self(duration)
+    }
+}
+
+impl FromStr for Delay {
+    type Err = TrafficShapingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || TrafficShapingError::InvalidDuration(s.to_string());
+
+        let (value, unit) = split_unit(s).ok_or_else(invalid)?;
+        let nanos_per_unit = match unit {
+            "ns" => 1.0,
+            "us" | "µs" => 1_000.0,
+            "" | "ms" => 1_000_000.0,
+            "s" => 1_000_000_000.0,
+            "min" => 60_000_000_000.0,
+            _ => return Err(invalid()),
+        };
+
+        Ok(Self(Duration::from_nanos(
+            (value * nanos_per_unit).round() as u64
+        )))
+    }
+}
+
+/// Renders the delay in the largest unit dividing it, like `250ms` or `1s`,
+/// which tc parses as well
+impl fmt::Display for Delay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let nanos = self.0.as_nanos();
+        if nanos == 0 {
+            return write!(f, "0ms");
+        }
+        let units = [(1_000_000_000, "s"), (1_000_000, "ms"), (1_000, "us")];
+        match units.iter().find(|(scale, _)| nanos.is_multiple_of(*scale)) {
+            Some((scale, unit)) => write!(f, "{}{}", nanos / scale, unit),
+            None => write!(f, "{}ns", nanos),
+        }
+    }
+}
+
+/// Splits a value from its unit, like `1.5` from `MB/s`, checking the value
+/// is a non-negative number
+fn split_unit(s: &str) -> Option<(f64, &str)> {
+    let s = s.trim();
+    let end = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let value: f64 = s[..end].parse().ok()?;
+    value.is_finite().then_some((value, s[end..].trim()))
+}
+
+/// A value as written in a manifest, a bare number in the default unit or a
+/// string parsed by `from_str`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum UnitSpec {
+    Number(u64),
+    Text(String),
+}
+
+impl TryFrom<UnitSpec> for Bandwidth {
+    type Error = TrafficShapingError;
+
+    fn try_from(spec: UnitSpec) -> Result<Self, Self::Error> {
+        match spec {
+            UnitSpec::Number(bits_per_second) => Ok(Self::from_bps(bits_per_second)),
+            UnitSpec::Text(s) => s.parse(),
+        }
+    }
+}
+
+impl TryFrom<UnitSpec> for Delay {
+    type Error = TrafficShapingError;
+
+    fn try_from(spec: UnitSpec) -> Result<Self, Self::Error> {
+        match spec {
+            UnitSpec::Number(millis) => Ok(Self::from_millis(millis)),
+            UnitSpec::Text(s) => s.parse(),
+        }
+    }
+}
+
+impl Serialize for Bandwidth {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl Serialize for Delay {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bps(s: &str) -> u64 {
+        s.parse::<Bandwidth>().unwrap().bps()
+    }
+
+    fn delay(s: &str) -> Duration {
+        s.parse::<Delay>().unwrap().as_duration()
+    }
+
+    #[test]
+    fn parses_bandwidths_with_units() {
+        assert_eq!(bps("10Mbit"), 10_000_000);
+        assert_eq!(bps("1.5MB/s"), 12_000_000);
+        assert_eq!(bps("512kbps"), 512_000);
+        assert_eq!(bps("1Gbit/s"), 1_000_000_000);
+        assert_eq!(bps("64KiB"), 64 * 1024 * 8);
+        assert_eq!(bps("2 Mibit"), 2 * 1024 * 1024);
+        assert_eq!(bps("100 bytes"), 800);
+        assert_eq!(
+            "unlimited".parse::<Bandwidth>().unwrap(),
+            Bandwidth::UNLIMITED
+        );
+    }
+
+    #[test]
+    fn plain_bandwidths_are_bits_per_second() {
+        assert_eq!(bps("1000000"), 1_000_000);
+        assert_eq!(bps("0"), 0);
+    }
+
+    #[test]
+    fn parses_delays_with_units() {
+        assert_eq!(delay("250ms"), Duration::from_millis(250));
+        assert_eq!(delay("1.2s"), Duration::from_millis(1200));
+        assert_eq!(delay("500us"), Duration::from_micros(500));
+        assert_eq!(delay("1min"), Duration::from_secs(60));
+        assert_eq!(delay(" 10 ms "), Duration::from_millis(10));
+    }
+
+    #[test]
+    fn plain_delays_are_milliseconds() {
+        assert_eq!(delay("80"), Duration::from_millis(80));
+        assert_eq!(delay("0.5"), Duration::from_micros(500));
+    }
+
+    #[test]
+    fn refuses_invalid_units() {
+        for s in [
+            "10Mbitz",
+            "10Tbit",
+            "fast",
+            "",
+            "-1Mbit",
+            "Mbit",
+            "1.2.3Mbit",
+            "0.4bit",
+        ] {
+            assert!(
+                matches!(
+                    s.parse::<Bandwidth>(),
+                    Err(TrafficShapingError::InvalidBandwidth(_))
+                ),
+                "{}",
+                s
+            );
+        }
+        for s in ["250mss", "1h", "soon", "", "-5ms", "ms"] {
+            assert!(
+                matches!(
+                    s.parse::<Delay>(),
+                    Err(TrafficShapingError::InvalidDuration(_))
+                ),
+                "{}",
+                s
+            );
+        }
+    }
+
+    #[test]
+    fn display_parses_back() {
+        for bandwidth in [0, 1, 999, 1_000, 1_500_000, 10_000_000, 2_000_000_000] {
+            let bandwidth = Bandwidth::from_bps(bandwidth);
+            assert_eq!(
+                bandwidth.to_string().parse::<Bandwidth>().unwrap(),
+                bandwidth
+            );
+        }
+        assert_eq!(Bandwidth::from_bps(10_000_000).to_string(), "10Mbit");
+        assert_eq!(Bandwidth::from_bps(1_500_000).to_string(), "1500Kbit");
+
+        for duration in [
+            Duration::ZERO,
+            Duration::from_nanos(1),
+            Duration::from_micros(1_500),
+            Duration::from_millis(250),
+            Duration::from_secs(2),
+        ] {
+            let delay = Delay::from(duration);
+            assert_eq!(delay.to_string().parse::<Delay>().unwrap(), delay);
+        }
+        assert_eq!(Delay::from_millis(250).to_string(), "250ms");
+        assert_eq!(Delay::from_millis(1_200).to_string(), "1200ms");
+    }
+
+    #[test]
+    fn reads_numbers_and_strings_from_manifests() {
+        let bandwidth: Bandwidth = serde_json::from_str("1000000").unwrap();
+        assert_eq!(bandwidth.bps(), 1_000_000);
+        let bandwidth: Bandwidth = serde_json::from_str(r#""1.5MB/s""#).unwrap();
+        assert_eq!(serde_json::to_string(&bandwidth).unwrap(), r#""12Mbit""#);
+
+        let delay: Delay = serde_json::from_str("250").unwrap();
+        assert_eq!(delay, Delay::from_millis(250));
+        assert!(serde_json::from_str::<Delay>(r#""1 fortnight""#).is_err());
+    }
+}